@@ -0,0 +1,169 @@
+use std::fmt::Write;
+
+use crate::ConfigAdaptive;
+
+/// How the link to a peer is doing, from the RTCP receiver reports
+/// it sends back
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Conditions {
+    /// Fraction of the packets lost, from 0 to 1
+    pub(crate) loss: f64,
+    /// Round trip time, in seconds
+    pub(crate) rtt: f64,
+}
+
+impl Conditions {
+    /// Out of the reply to webrtcbin's `get-stats'.  The peer reports
+    /// on each stream it gets in a `remote-inbound-rtp' entry, the only
+    /// ones with a round trip time, and the worst of them is taken.
+    pub(crate) fn from_stats(stats: &gst::StructureRef) -> Option<Self> {
+        let mut found: Option<Conditions> = None;
+        for (_, value) in stats.iter() {
+            let entry = match value.get::<gst::Structure>() {
+                Ok(Some(entry)) => entry,
+                _ => continue,
+            };
+            let rtt = match entry.get_some::<f64>("round-trip-time") {
+                Ok(rtt) => rtt,
+                Err(_) => continue,
+            };
+            let loss = entry.get_some::<f64>("fraction-lost").unwrap_or(0.0);
+            let conditions = Conditions { loss, rtt };
+            found = Some(found.map_or(conditions, |found| found.worse(conditions)));
+        }
+        found
+    }
+
+    pub(crate) fn worse(self, other: Self) -> Self {
+        Conditions {
+            loss: self.loss.max(other.loss),
+            rtt: self.rtt.max(other.rtt),
+        }
+    }
+
+    /// Past the loss or round trip time the encoders back off at
+    pub(crate) fn congested(&self, config: &ConfigAdaptive) -> bool {
+        self.loss * 100.0 > config.max_loss || self.rtt * 1000.0 > config.max_rtt as f64
+    }
+
+    /// Well below both, which is when the encoders aim higher again
+    fn clear(&self, config: &ConfigAdaptive) -> bool {
+        self.loss * 100.0 < config.max_loss / 5.0 && self.rtt * 1000.0 < config.max_rtt as f64 / 2.0
+    }
+}
+
+/// Bitrate an encoder aims at, in kbit/s.  It goes down quickly when
+/// the peers getting its video struggle, and back up slowly, to what
+/// was configured at most, once they don't.
+#[derive(Debug)]
+pub(crate) struct Bitrate {
+    pub(crate) current: u32,
+    max: u32,
+    min: u32,
+    /// Times it was lowered and raised
+    pub(crate) lowered: u64,
+    pub(crate) raised: u64,
+}
+
+impl Bitrate {
+    pub(crate) fn new(max: u32, min: u32) -> Self {
+        Bitrate {
+            current: max,
+            max,
+            min: min.min(max),
+            lowered: 0,
+            raised: 0,
+        }
+    }
+
+    pub(crate) fn at_min(&self) -> bool {
+        self.current == self.min
+    }
+
+    /// Follow the conditions of the worst peer, the new bitrate comes
+    /// back when it changes
+    pub(crate) fn adapt(&mut self, config: &ConfigAdaptive, worst: Conditions) -> Option<u32> {
+        let next = if worst.congested(config) {
+            ((self.current as f64 * 0.7) as u32).max(self.min)
+        } else if worst.clear(config) {
+            ((self.current as f64 * 1.1) as u32 + 1).min(self.max)
+        } else {
+            self.current
+        };
+        if next < self.current {
+            self.lowered += 1;
+        } else if next > self.current {
+            self.raised += 1;
+        } else {
+            return None;
+        }
+        self.current = next;
+        Some(next)
+    }
+}
+
+/// Where the encoders of each quality are at
+#[derive(Debug)]
+pub(crate) struct Adaptation {
+    pub(crate) high: Bitrate,
+    pub(crate) low: Option<Bitrate>,
+    /// Peers moved to the low quality for struggling with the high
+    /// one at its lowest bitrate
+    pub(crate) downgrades: u64,
+}
+
+/// Bitrates, and the conditions of the peers they follow, in the
+/// Prometheus text format
+pub(crate) fn metrics(adaptation: &Adaptation, peers: &[(String, Conditions)]) -> String {
+    let mut bitrates = String::new();
+    let mut changes = String::new();
+    let mut loss = String::new();
+    let mut rtt = String::new();
+
+    let encoders = std::iter::once(("high", &adaptation.high))
+        .chain(adaptation.low.as_ref().map(|low| ("low", low)));
+    for (quality, bitrate) in encoders {
+        let _ = writeln!(
+            bitrates,
+            "capture_encoder_bitrate_kbps{{quality=\"{}\"}} {}",
+            quality, bitrate.current
+        );
+        let _ = writeln!(
+            changes,
+            "capture_bitrate_changes_total{{quality=\"{}\",direction=\"down\"}} {}",
+            quality, bitrate.lowered
+        );
+        let _ = writeln!(
+            changes,
+            "capture_bitrate_changes_total{{quality=\"{}\",direction=\"up\"}} {}",
+            quality, bitrate.raised
+        );
+    }
+    for (peer, conditions) in peers {
+        let _ = writeln!(
+            loss,
+            "capture_peer_packet_loss{{peer=\"{}\"}} {}",
+            peer, conditions.loss
+        );
+        let _ = writeln!(
+            rtt,
+            "capture_peer_round_trip_seconds{{peer=\"{}\"}} {}",
+            peer, conditions.rtt
+        );
+    }
+
+    format!(
+        "# HELP capture_encoder_bitrate_kbps Bitrate the encoder of each quality aims at\n\
+         # TYPE capture_encoder_bitrate_kbps gauge\n{}\
+         # HELP capture_bitrate_changes_total Times the bitrate of an encoder was lowered or raised\n\
+         # TYPE capture_bitrate_changes_total counter\n{}\
+         # HELP capture_peer_downgrades_total Peers moved to the low quality for struggling with the high one\n\
+         # TYPE capture_peer_downgrades_total counter\n\
+         capture_peer_downgrades_total {}\n\
+         # HELP capture_peer_packet_loss Fraction of the packets the peer reported lost\n\
+         # TYPE capture_peer_packet_loss gauge\n{}\
+         # HELP capture_peer_round_trip_seconds Round trip time to the peer\n\
+         # TYPE capture_peer_round_trip_seconds gauge\n{}",
+        bitrates, changes, adaptation.downgrades, loss, rtt,
+    )
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::err::Error;
+use crate::{ssl_connector, Config};
+
+/// Token the server issued to the device, presented to the server
+/// when connecting and to storage when recording
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Token {
+    pub(crate) token: String,
+    /// When it expires, in seconds since the UNIX epoch
+    pub(crate) exp: u64,
+}
+
+impl Token {
+    /// How long until it's time to get a new one.  A tenth of what's
+    /// left of it, and at least 30 seconds, is kept as a margin.
+    pub(crate) fn refresh_in(&self, now: u64) -> Duration {
+        refresh_in(self.exp, now)
+    }
+}
+
+/// How long until it's time to replace something that expires at
+/// `exp', in seconds since the UNIX epoch
+pub(crate) fn refresh_in(exp: u64, now: u64) -> Duration {
+    let left = exp.saturating_sub(now);
+    let margin = (left / 10).max(30).min(left);
+    Duration::from_secs(left - margin)
+}
+
+#[derive(Serialize)]
+struct Credential<'a> {
+    jid: &'a str,
+    password: &'a str,
+}
+
+/// The HTTP endpoints, like `/auth', sit next to `/ws' on the server
+pub(crate) fn server_url(server: &str, path: &str) -> String {
+    let base = server.trim_end_matches('/').trim_end_matches("/ws");
+    let base = if let Some(rest) = base.strip_prefix("wss://") {
+        format!("https://{}", rest)
+    } else if let Some(rest) = base.strip_prefix("ws://") {
+        format!("http://{}", rest)
+    } else {
+        base.to_string()
+    };
+    format!("{}{}", base, path)
+}
+
+/// Trade the secret of the device for a token
+pub(crate) async fn login(config: &Config, secret: &str) -> Result<Token, Error> {
+    let connector = awc::Connector::new()
+        .timeout(Duration::from_secs(15))
+        .openssl(ssl_connector(config)?);
+    let client = awc::Client::builder().connector(connector).finish();
+    let mut response = client
+        .post(server_url(&config.http.server, "/auth"))
+        .send_json(&Credential {
+            jid: &config.http.jid,
+            password: secret,
+        })
+        .await
+        .map_err(|e| Error::new_io(format!("Can't reach the server: {}", e)))?;
+    if !response.status().is_success() {
+        return Err(Error::new_io(format!(
+            "Server didn't take the secret: {}",
+            response.status()
+        )));
+    }
+    response
+        .json::<Token>()
+        .await
+        .map_err(|e| Error::new_proto(format!("Can't read token: {}", e)))
+}
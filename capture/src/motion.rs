@@ -0,0 +1,93 @@
+use crate::ConfigMotion;
+
+/// What changed since the last frame looked at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Motion {
+    Start,
+    Stop,
+}
+
+/// Finds motion by comparing each downscaled grayscale frame with the
+/// one before it.  A pixel changed when its brightness moved more
+/// than `threshold', and there's motion when more than `min_area'
+/// percent of the pixels outside of the masks changed.  Motion is
+/// over once there's none for `stop_after' seconds.
+pub(crate) struct Detector {
+    width: usize,
+    height: usize,
+    threshold: u8,
+    /// Pixels that have to change for it to count as motion
+    min_changed: usize,
+    /// Whether each pixel is looked at, false within the masks
+    watched: Vec<bool>,
+    stop_after: u64,
+    previous: Option<Vec<u8>>,
+    /// Last time there was motion, while there's motion
+    moving: Option<u64>,
+}
+
+impl Detector {
+    pub(crate) fn new(config: &ConfigMotion) -> Self {
+        let (width, height) = (config.width as usize, config.height as usize);
+        let mut watched = vec![true; width * height];
+        for mask in &config.masks {
+            let x0 = (mask.x * width as f64) as usize;
+            let y0 = (mask.y * height as f64) as usize;
+            let x1 = (((mask.x + mask.width) * width as f64) as usize).min(width);
+            let y1 = (((mask.y + mask.height) * height as f64) as usize).min(height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    watched[y * width + x] = false;
+                }
+            }
+        }
+        let area = watched.iter().filter(|w| **w).count();
+        Self {
+            width,
+            height,
+            threshold: config.threshold,
+            min_changed: ((area as f64 * config.min_area / 100.0) as usize).max(1),
+            watched,
+            stop_after: config.stop_after * 1000,
+            previous: None,
+            moving: None,
+        }
+    }
+
+    /// Look at a GRAY8 frame taken at `now', in milliseconds.  Rows
+    /// are padded to 4 bytes, as GStreamer lays them out.
+    pub(crate) fn feed(&mut self, data: &[u8], now: u64) -> Option<Motion> {
+        let stride = (self.width + 3) & !3;
+        if data.len() < stride * self.height {
+            return None;
+        }
+        let mut frame = Vec::with_capacity(self.width * self.height);
+        for row in data.chunks(stride).take(self.height) {
+            frame.extend_from_slice(&row[..self.width]);
+        }
+
+        let changed = match self.previous.replace(frame) {
+            Some(previous) => previous
+                .iter()
+                .zip(self.previous.as_ref().unwrap())
+                .zip(&self.watched)
+                .filter(|((a, b), watched)| **watched && a.abs_diff(**b) > self.threshold)
+                .count(),
+            None => return None,
+        };
+
+        if changed >= self.min_changed {
+            return match self.moving.replace(now) {
+                None => Some(Motion::Start),
+                Some(_) => None,
+            };
+        }
+        match self.moving {
+            Some(last) if now.saturating_sub(last) >= self.stop_after => {
+                self.moving = None;
+                Some(Motion::Stop)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::SinkExt;
+use gst::prelude::*;
+
+use crate::err::Error;
+use crate::ConfigStorage;
+
+/// Name of the application messages recorders post on the bus of the
+/// main pipeline
+pub(crate) const RECORDER_MESSAGE: &str = "ucam-recorder";
+
+/// Header that carries the hex encoded SHA-256 of an uploaded chunk
+const CHECKSUM_HEADER: &str = "X-Checksum-Sha256";
+
+/// Extension of the chunk files, same as the ones written by storage
+const CHUNK_EXTENSION: &str = "mp4";
+
+/// Size of the pieces chunks are read in to be hashed and uploaded
+const UPLOAD_PIECE_SIZE: usize = 256 * 1024;
+
+/// Id of the next recorder built
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Where the recording stream is currently going to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RecordMode {
+    /// Pushing the stream to storage via RTSP
+    Remote,
+    /// Writing chunks into the local buffer while storage can't be
+    /// reached
+    Local,
+    /// Writing an event clip into the local buffer, uploaded once
+    /// it's finished
+    Clip,
+}
+
+impl RecordMode {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RecordMode::Remote => "remote",
+            RecordMode::Local => "local",
+            RecordMode::Clip => "clip",
+        }
+    }
+}
+
+/// Pipeline fed with the encoded stream that gets recorded.  It's
+/// kept apart from the main pipeline so a storage that goes away
+/// can't take the live stream down with it.  Anything worth knowing
+/// about it is posted on the bus of the main pipeline as an
+/// application message named `ucam-recorder', along with the id of
+/// the recorder it's about.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    pub(crate) id: u64,
+    pub(crate) mode: RecordMode,
+    pipeline: gst::Pipeline,
+    appsrc: gst_app::AppSrc,
+    offset: Mutex<Option<gst::ClockTime>>,
+}
+
+impl Recorder {
+    /// Storage takes the same token the server issued to the device,
+    /// when it has one, in the RTSP location
+    pub(crate) fn new(
+        mode: RecordMode,
+        config: &ConfigStorage,
+        main: &gst::Pipeline,
+        token: Option<&str>,
+    ) -> Result<Self, Error> {
+        Self::build(mode, config, main, now_ms(), token)
+    }
+
+    /// Recorder for an event clip whose first frame was taken at
+    /// `start', in milliseconds since the UNIX epoch
+    pub(crate) fn new_clip(
+        config: &ConfigStorage,
+        main: &gst::Pipeline,
+        start: u64,
+    ) -> Result<Self, Error> {
+        Self::build(RecordMode::Clip, config, main, start, None)
+    }
+
+    fn build(
+        mode: RecordMode,
+        config: &ConfigStorage,
+        main: &gst::Pipeline,
+        start: u64,
+        token: Option<&str>,
+    ) -> Result<Self, Error> {
+        let sink = match mode {
+            RecordMode::Remote => match token {
+                Some(token) => format!(
+                    "rtspclientsink name=sink location=\"{}?token={}\"",
+                    config.server, token
+                ),
+                None => format!("rtspclientsink name=sink location={}", config.server),
+            },
+            RecordMode::Local | RecordMode::Clip => {
+                std::fs::create_dir_all(&config.buffer_path)?;
+                format!(
+                    "splitmuxsink name=sink max-size-time={}",
+                    config.chunk_duration * gst::SECOND_VAL,
+                )
+            }
+        };
+        let pipeline = gst::parse_launch(&format!(
+            "appsrc name=src format=time is-live=true ! h264parse ! {}",
+            sink
+        ))?
+        .downcast::<gst::Pipeline>()
+        .expect("not a pipeline");
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let appsrc = pipeline
+            .get_by_name("src")
+            .expect("can't find src")
+            .downcast::<gst_app::AppSrc>()
+            .expect("src isn't an appsrc");
+
+        // Chunks written locally are named the same way storage names
+        // them, so they fall in the right place once uploaded.  Clips
+        // start with footage from the past, so their chunks are named
+        // after the time of their first frame rather than the time
+        // they're written at.
+        let dir = PathBuf::from(&config.buffer_path);
+        match mode {
+            RecordMode::Local => {
+                pipeline
+                    .get_by_name("sink")
+                    .expect("can't find sink")
+                    .connect("format-location", false, move |_values| {
+                        let location = dir.join(chunk_name(now_ms()));
+                        Some(location.to_string_lossy().to_string().to_value())
+                    })?;
+            }
+            RecordMode::Clip => {
+                pipeline
+                    .get_by_name("sink")
+                    .expect("can't find sink")
+                    .connect("format-location-full", false, move |values| {
+                        let offset = values[2]
+                            .get::<gst::Sample>()
+                            .ok()
+                            .flatten()
+                            .and_then(|sample| sample.get_buffer().map(|b| b.get_pts()))
+                            .and_then(|pts| pts.mseconds())
+                            .unwrap_or(0);
+                        let location = dir.join(chunk_name(start + offset));
+                        Some(location.to_string_lossy().to_string().to_value())
+                    })?;
+            }
+            RecordMode::Remote => {}
+        }
+
+        // Nobody is polling this pipeline's bus, so the messages that
+        // matter are forwarded to the main one
+        let main_clone = main.clone();
+        pipeline
+            .get_bus()
+            .expect("Pipeline without bus. Shouldn't happen!")
+            .set_sync_handler(move |_bus, msg| {
+                use gst::message::MessageView;
+
+                match msg.view() {
+                    MessageView::Error(err) => {
+                        warn!(
+                            "Recorder ({}) failed: {} ({})",
+                            mode.as_str(),
+                            err.get_error(),
+                            err.get_debug().unwrap_or_else(|| String::from("None")),
+                        );
+                        notify(&main_clone, id, mode, "error");
+                    }
+                    MessageView::Eos(_) => notify(&main_clone, id, mode, "eos"),
+                    MessageView::Element(element) => {
+                        let closed = element
+                            .get_structure()
+                            .map(|s| s.get_name() == "splitmuxsink-fragment-closed")
+                            .unwrap_or(false);
+                        if closed {
+                            notify(&main_clone, id, mode, "fragment-closed");
+                        }
+                    }
+                    _ => (),
+                }
+                gst::BusSyncReply::Drop
+            });
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|_| Error::new_gst(format!("Can't start {} recorder", mode.as_str())))?;
+
+        Ok(Self {
+            id,
+            mode,
+            pipeline,
+            appsrc,
+            offset: Mutex::new(None),
+        })
+    }
+
+    /// Feed an encoded sample taken from the main pipeline.  The
+    /// recording starts at the first key frame and its timestamps are
+    /// shifted so it starts at zero.
+    pub(crate) fn push(&self, sample: &gst::Sample) {
+        let buffer = match sample.get_buffer() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+
+        let mut offset = self.offset.lock().unwrap();
+        let start = match *offset {
+            Some(start) => start,
+            None => {
+                if buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                    return;
+                }
+                if let Some(caps) = sample.get_caps() {
+                    self.appsrc.set_caps(Some(&caps.to_owned()));
+                }
+                *offset = Some(buffer.get_dts_or_pts());
+                buffer.get_dts_or_pts()
+            }
+        };
+        drop(offset);
+
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            if buffer.get_pts().is_some() {
+                buffer.set_pts(buffer.get_pts() - start);
+            }
+            if buffer.get_dts().is_some() {
+                buffer.set_dts(buffer.get_dts() - start);
+            }
+        }
+
+        // Failures are reported on the bus, there's nothing to do here
+        let _ = self.appsrc.push_buffer(buffer);
+    }
+
+    /// Ask the recorder to wrap up what it's writing.  It posts `eos'
+    /// once it's done and can be dropped.
+    pub(crate) fn finish(&self) {
+        let _ = self.appsrc.end_of_stream();
+    }
+}
+
+// Make sure to shut down the pipeline when it goes out of scope
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// The last seconds of the encoded stream, kept in memory so event
+/// clips can start a little before whatever triggered them.  It's
+/// trimmed a whole group of pictures at a time so it always starts
+/// at a key frame, which means it holds up to one key frame interval
+/// more than asked for.
+#[derive(Debug)]
+pub(crate) struct PreRoll {
+    /// How far back it goes, in milliseconds
+    duration: u64,
+    /// Samples along with the wall-clock time they were taken at
+    samples: VecDeque<(u64, gst::Sample)>,
+}
+
+impl PreRoll {
+    pub(crate) fn new(seconds: u64) -> Self {
+        Self {
+            duration: seconds * 1000,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Keep a sample taken at `now' and drop what's too old
+    pub(crate) fn push(&mut self, sample: gst::Sample, now: u64) {
+        if self.samples.is_empty() && !is_key_frame(&sample) {
+            return;
+        }
+        self.samples.push_back((now, sample));
+
+        let cutoff = now.saturating_sub(self.duration);
+        loop {
+            let next = self
+                .samples
+                .iter()
+                .skip(1)
+                .position(|(_, sample)| is_key_frame(sample))
+                .map(|i| i + 1);
+            match next {
+                Some(next) if self.samples[next].0 <= cutoff => {
+                    self.samples.drain(..next);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Hand over everything kept so far, oldest first
+    pub(crate) fn take(&mut self) -> Vec<(u64, gst::Sample)> {
+        self.samples.drain(..).collect()
+    }
+}
+
+fn is_key_frame(sample: &gst::Sample) -> bool {
+    sample
+        .get_buffer()
+        .map(|b| !b.get_flags().contains(gst::BufferFlags::DELTA_UNIT))
+        .unwrap_or(false)
+}
+
+/// Post an application message about a recorder on the main pipeline
+fn notify(main: &gst::Pipeline, id: u64, mode: RecordMode, event: &str) {
+    let structure = gst::Structure::builder(RECORDER_MESSAGE)
+        .field("id", &id)
+        .field("mode", &mode.as_str())
+        .field("event", &event)
+        .build();
+    let _ = main.post_message(&gst::Message::new_application(structure).build());
+}
+
+/// Chunks are named after the wall-clock time, in milliseconds, in
+/// which they started
+fn chunk_name(start: u64) -> String {
+    format!("{}.{}", start, CHUNK_EXTENSION)
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Chunks sitting in the local buffer, oldest first
+pub(crate) fn buffered_chunks(dir: &str) -> Result<Vec<PathBuf>, Error> {
+    let dir = Path::new(dir);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut chunks = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter_map(|path| {
+            let start = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(CHUNK_EXTENSION)?
+                .strip_suffix('.')?
+                .parse::<u64>()
+                .ok()?;
+            Some((start, path))
+        })
+        .collect::<Vec<_>>();
+    chunks.sort();
+    Ok(chunks.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Keep the local buffer within `buffer_maxspace' bytes by deleting
+/// the oldest chunks
+pub(crate) fn enforce_maxspace(config: &ConfigStorage) -> Result<(), Error> {
+    let chunks = buffered_chunks(&config.buffer_path)?
+        .into_iter()
+        .map(|path| {
+            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            (path, size)
+        })
+        .collect::<Vec<_>>();
+    let mut used: u64 = chunks.iter().map(|(_, size)| size).sum();
+
+    for (path, size) in chunks {
+        if used <= config.buffer_maxspace {
+            break;
+        }
+        warn!("Local buffer is full, dropping {:?}", path);
+        std::fs::remove_file(&path)?;
+        used -= size;
+    }
+    Ok(())
+}
+
+/// HTTP client for the storage API
+pub(crate) fn storage_client(connector: openssl::ssl::SslConnector) -> awc::Client {
+    let connector = awc::Connector::new()
+        .timeout(Duration::from_secs(15))
+        .openssl(connector);
+    awc::Client::builder()
+        .connector(connector)
+        .timeout(Duration::from_secs(120))
+        .finish()
+}
+
+/// Check if the storage API can be reached
+pub(crate) async fn probe(client: &awc::Client, config: &ConfigStorage) -> Result<(), Error> {
+    let url = format!("{}/status", config.api.trim_end_matches('/'));
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| Error::new_io(format!("Can't reach storage: {}", e)))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(Error::new_io(format!(
+            "Storage isn't ready: {}",
+            response.status()
+        )))
+    }
+}
+
+/// Upload a chunk from the local buffer and remove it once storage
+/// has confirmed it has the exact same bytes.  The file is hashed and
+/// then sent in pieces, read off the event loop, so it's never held
+/// in memory as a whole.
+pub(crate) async fn upload(
+    client: &awc::Client,
+    config: &ConfigStorage,
+    jid: &str,
+    token: Option<&str>,
+    path: &Path,
+) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::new_input(format!("Invalid chunk path {:?}", path)))?
+        .to_string();
+    let hashed = path.to_path_buf();
+    let checksum = actix_rt::task::spawn_blocking(move || file_checksum(&hashed))
+        .await
+        .map_err(|e| Error::new_io(format!("Can't hash {}: {}", name, e)))??;
+
+    let url = format!(
+        "{}/upload/{}/{}",
+        config.api.trim_end_matches('/'),
+        jid,
+        name
+    );
+    let mut request = client.put(&url).insert_header((CHECKSUM_HEADER, checksum));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send_stream(read_pieces(path.to_path_buf()))
+        .await
+        .map_err(|e| Error::new_io(format!("Can't upload {}: {}", name, e)))?;
+    if !response.status().is_success() {
+        return Err(Error::new_io(format!(
+            "Storage refused {}: {}",
+            name,
+            response.status()
+        )));
+    }
+
+    std::fs::remove_file(path)?;
+    info!("Chunk {} backfilled", name);
+    Ok(())
+}
+
+/// Hex encoded SHA-256 of a file
+fn file_checksum(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = openssl::sha::Sha256::new();
+    let mut buf = vec![0; UPLOAD_PIECE_SIZE];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex(&hasher.finish()))
+}
+
+/// Stream of the contents of a file, read in a blocking thread a few
+/// pieces ahead of what's been sent
+fn read_pieces(path: PathBuf) -> mpsc::Receiver<Result<bytes::Bytes, std::io::Error>> {
+    let (mut sender, receiver) = mpsc::channel(4);
+    actix_rt::task::spawn_blocking(move || {
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                let _ = futures::executor::block_on(sender.send(Err(err)));
+                return;
+            }
+        };
+        loop {
+            let mut buf = vec![0; UPLOAD_PIECE_SIZE];
+            let piece = match file.read(&mut buf) {
+                Ok(0) => return,
+                Ok(read) => {
+                    buf.truncate(read);
+                    Ok(bytes::Bytes::from(buf))
+                }
+                Err(err) => Err(err),
+            };
+            let failed = piece.is_err();
+            // The upload is gone when nobody is receiving anymore
+            if futures::executor::block_on(sender.send(piece)).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(key: bool) -> gst::Sample {
+        let mut buffer = gst::Buffer::new();
+        if !key {
+            buffer
+                .get_mut()
+                .unwrap()
+                .set_flags(gst::BufferFlags::DELTA_UNIT);
+        }
+        gst::Sample::new().buffer(&buffer).build()
+    }
+
+    fn times(preroll: &mut PreRoll) -> Vec<u64> {
+        preroll.take().into_iter().map(|(time, _)| time).collect()
+    }
+
+    #[test]
+    fn pre_roll_starts_at_a_key_frame() {
+        gst::init().unwrap();
+        let mut preroll = PreRoll::new(2);
+        preroll.push(frame(false), 0);
+        preroll.push(frame(false), 500);
+        preroll.push(frame(true), 1000);
+        preroll.push(frame(false), 1500);
+        assert_eq!(times(&mut preroll), vec![1000, 1500]);
+        assert!(preroll.take().is_empty());
+    }
+
+    #[test]
+    fn pre_roll_drops_whole_groups_of_pictures() {
+        gst::init().unwrap();
+        let mut preroll = PreRoll::new(2);
+        // A key frame every 2 seconds, a frame every half a second
+        for time in (0..=5000).step_by(500) {
+            preroll.push(frame(time % 2000 == 0), time);
+        }
+        assert_eq!(
+            times(&mut preroll),
+            vec![2000, 2500, 3000, 3500, 4000, 4500, 5000],
+        );
+    }
+}
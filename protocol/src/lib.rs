@@ -11,13 +11,27 @@ pub struct Envelope {
     /// JID value.
     pub from_jid: String,
 
-    /// The JID of the user receiving this message.
+    /// The JID of the user receiving this message.  Messages with no
+    /// JID here are sent to every connected peer but the sender, as
+    /// long as they're notifications like `RecordingGap', `MotionStart',
+    /// `MotionStop' and `EventClip'.  The server drops anything else.
     pub to_jid: String,
 
     /// The body of the message to be exchanged
     pub message: Message,
 }
 
+/// Quality of the video a device sends on a call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    #[default]
+    High,
+    /// For peers on slow links, when the device has a lighter
+    /// encoding of its video
+    Low,
+}
+
 /// Message is the struct that carries the different types of
 /// information clients exchange with the server and other clients
 #[derive(Debug, Deserialize, Serialize)]
@@ -40,6 +54,11 @@ pub enum Message {
     /// message of the type *offer*.
     PeerRequestCall,
 
+    /// Pick the quality of the video a device sends you, before
+    /// requesting the call or in the middle of it to switch.  Calls
+    /// are in high quality otherwise.
+    PeerQuality(Quality),
+
     /// Exchange text messages between peers
     PeerChat(String),
 
@@ -56,4 +75,44 @@ pub enum Message {
         #[serde(rename = "sdpMLineIndex")]
         sdp_mline_index: u32,
     },
+
+    /// Storage noticed a device stopped recording, or that it's
+    /// recording again.  Times are in milliseconds since the UNIX
+    /// epoch, and there's no `end' while the device still isn't
+    /// recording.
+    RecordingGap {
+        device: String,
+        start: u64,
+        end: Option<u64>,
+    },
+
+    /// A device started seeing motion, at a time in milliseconds
+    /// since the UNIX epoch
+    MotionStart { timestamp: u64 },
+
+    /// The motion a device was seeing is over
+    MotionStop { timestamp: u64 },
+
+    /// Ask a device recording in event mode to record a clip right
+    /// away, as if it had seen motion
+    Trigger,
+
+    /// A device recording in event mode finished a clip.  Times are
+    /// in milliseconds since the UNIX epoch, and `trigger' is what
+    /// made the device record it, `motion' or `manual'.
+    EventClip {
+        start: u64,
+        end: u64,
+        trigger: String,
+    },
+
+    /// Flag something that happened on a device at a time in
+    /// milliseconds since the UNIX epoch.  The server keeps it and
+    /// sends it to every other peer, storage included, which keeps
+    /// the footage around it from being deleted.
+    Bookmark {
+        device: String,
+        timestamp: u64,
+        label: String,
+    },
 }
@@ -1,36 +1,76 @@
 use std::time::{Duration, SystemTime};
 
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use openssl::nid::Nid;
+use openssl::x509::X509Ref;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::err::Error;
-use crate::model::Config;
+use crate::model::{Config, Role};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Claims {
     sub: String,
-    role: String,
+    role: Role,
     exp: usize,
 }
 
-pub(crate) fn create_token(config: &Config, jid: &str) -> Result<String, Error> {
+/// Token for a JID along with when it expires, in seconds since the
+/// UNIX epoch.  Storage tells devices and viewers apart by its role.
+pub(crate) fn create_token(config: &Config, jid: &str, role: Role) -> Result<(String, u64), Error> {
+    let exp = token_expiration(config.userauth.token_validity)?;
     let claims = Claims {
         sub: jid.to_string(),
-        role: "peer".to_string(),
-        exp: token_expiration(config.userauth.token_validity)? as usize,
+        role,
+        exp: exp as usize,
     };
 
     let header = Header::new(Algorithm::HS512);
 
     let key = EncodingKey::from_secret(config.userauth.jwt_secret.as_bytes());
 
-    Ok(encode(&header, &claims, &key)?)
+    Ok((encode(&header, &claims, &key)?, exp))
 }
 
-fn token_expiration(seconds: u64) -> Result<u64, Error> {
+/// JID a token was issued to, as long as it's still valid
+pub(crate) fn decode_token(config: &Config, token: &str) -> Result<String, Error> {
+    let key = DecodingKey::from_secret(config.userauth.jwt_secret.as_bytes());
+    let data = decode::<Claims>(token, &key, &Validation::new(Algorithm::HS512))?;
+    Ok(data.claims.sub)
+}
+
+/// Whether a JID has to prove who it is with a secret.  Those can
+/// only get in with a token.
+pub(crate) fn has_credential(config: &Config, jid: &str) -> bool {
+    config.credentials.contains_key(jid)
+}
+
+/// Role of a JID whose secret matches the hex encoded SHA-256 kept
+/// in `credentials'.  JIDs without one don't get any.
+pub(crate) fn check_credential(config: &Config, jid: &str, secret: &str) -> Option<Role> {
+    let credential = config.credentials.get(jid)?;
+    let digest = openssl::sha::sha256(secret.as_bytes());
+    let hex = digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let expected = credential.secret.to_lowercase();
+    if hex.len() == expected.len() && openssl::memcmp::eq(hex.as_bytes(), expected.as_bytes()) {
+        Some(credential.role)
+    } else {
+        None
+    }
+}
+
+/// JID a client certificate was issued to, taken from the common
+/// name of its subject
+pub(crate) fn jid_from_certificate(cert: &X509Ref) -> Option<String> {
+    let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    Some(entry.data().as_utf8().ok()?.to_string())
+}
+
+/// `userauth.token_validity' is given in hours
+fn token_expiration(hours: u64) -> Result<u64, Error> {
     let epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    epoch
-        .checked_add(Duration::from_secs(seconds))
+    let exp = epoch
+        .checked_add(Duration::from_secs(hours * 3600))
         .ok_or_else(Error::new)?;
-    Ok(epoch.as_secs())
+    Ok(exp.as_secs())
 }
@@ -0,0 +1,76 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::model::Config;
+
+/// Something a peer flagged on the timeline of a device
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Bookmark {
+    /// JID of the peer that flagged it
+    pub(crate) from_jid: String,
+    pub(crate) device: String,
+    /// Milliseconds since the UNIX epoch
+    pub(crate) timestamp: u64,
+    pub(crate) label: String,
+}
+
+/// Bookmarks kept in memory and, when `bookmarks.path' is set, backed
+/// by a file in which each one is appended as a JSON line
+pub(crate) struct Bookmarks {
+    file: Option<File>,
+    entries: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    pub(crate) fn open(config: &Config) -> io::Result<Self> {
+        let path = match &config.bookmarks {
+            Some(bookmarks) => Path::new(&bookmarks.path),
+            None => {
+                return Ok(Self {
+                    file: None,
+                    entries: vec![],
+                })
+            }
+        };
+        let mut entries = vec![];
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                match serde_json::from_str::<Bookmark>(&line?) {
+                    Ok(bookmark) => entries.push(bookmark),
+                    Err(err) => warn!("Skipping broken bookmark in {:?}: {}", path, err),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(file),
+            entries,
+        })
+    }
+
+    pub(crate) fn add(&mut self, bookmark: Bookmark) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", serde_json::to_string(&bookmark)?)?;
+        }
+        self.entries.push(bookmark);
+        Ok(())
+    }
+
+    /// Bookmarks of a device, or of every device, oldest first
+    pub(crate) fn list(&self, device: Option<&str>) -> Vec<Bookmark> {
+        let mut found = self
+            .entries
+            .iter()
+            .filter(|b| match device {
+                Some(device) => b.device == device,
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<Bookmark>>();
+        found.sort_by_key(|b| b.timestamp);
+        found
+    }
+}
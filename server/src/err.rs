@@ -39,6 +39,12 @@ impl From<jsonwebtoken::errors::Error> for Error {
     }
 }
 
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(_err: openssl::error::ErrorStack) -> Self {
+        Error::new()
+    }
+}
+
 impl From<actix::MailboxError> for Error {
     fn from(_err: actix::MailboxError) -> Self {
         Error::new()
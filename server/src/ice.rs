@@ -0,0 +1,109 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_derive::Serialize;
+
+use crate::err::Error;
+use crate::model::ConfigICE;
+
+/// Same shape as WebRTC's `RTCIceServer', so browsers can take it
+/// as it is
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct IceServer {
+    urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+}
+
+/// ICE servers for a JID to use, with TURN credentials that expire
+/// after `ice.turn_ttl' seconds
+pub(crate) fn ice_servers(config: &ConfigICE, jid: &str) -> Result<Vec<IceServer>, Error> {
+    let mut servers = Vec::new();
+    if !config.stun_servers.is_empty() {
+        servers.push(IceServer {
+            urls: config.stun_servers.clone(),
+            username: None,
+            credential: None,
+        });
+    }
+    if !config.turn_servers.is_empty() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let (username, credential) = turn_credentials(config, jid, now)?;
+        servers.push(IceServer {
+            urls: config.turn_servers.clone(),
+            username: Some(username),
+            credential: Some(credential),
+        });
+    }
+    Ok(servers)
+}
+
+/// Time-limited credentials of the TURN REST API that coturn and
+/// other relays implement with `use-auth-secret'.  The username is
+/// the time they expire followed by the JID, and the password the
+/// base64 encoded HMAC-SHA1 of the username with the secret shared
+/// with the relay.
+fn turn_credentials(config: &ConfigICE, jid: &str, now: u64) -> Result<(String, String), Error> {
+    let exp = now + config.turn_ttl;
+    let username = format!("{}:{}", exp, jid);
+    let key = PKey::hmac(config.turn_secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(username.as_bytes())?;
+    let credential = base64::encode(signer.sign_to_vec()?);
+    Ok((username, credential))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(stun_servers: &[&str], turn_servers: &[&str]) -> ConfigICE {
+        ConfigICE {
+            stun_servers: stun_servers.iter().map(|s| s.to_string()).collect(),
+            turn_servers: turn_servers.iter().map(|s| s.to_string()).collect(),
+            turn_secret: "north".to_string(),
+            turn_ttl: 86400,
+        }
+    }
+
+    #[test]
+    fn turn_credentials_follow_the_turn_rest_api() {
+        let config = config(&[], &["turn:turn.example.com:3478"]);
+        let (username, credential) =
+            turn_credentials(&config, "cam001@studio.loc", 1_700_000_000).unwrap();
+        assert_eq!(username, "1700086400:cam001@studio.loc");
+        assert_eq!(credential, "SDpygnUdHA2yyvEa0BCS5/AqkmM=");
+    }
+
+    #[test]
+    fn turn_credentials_depend_on_the_secret() {
+        let mut other = config(&[], &["turn:turn.example.com:3478"]);
+        other.turn_secret = "south".to_string();
+        let (_, credential) = turn_credentials(&other, "cam001@studio.loc", 1_700_000_000).unwrap();
+        assert_ne!(credential, "SDpygnUdHA2yyvEa0BCS5/AqkmM=");
+    }
+
+    #[test]
+    fn only_turn_servers_get_credentials() {
+        let config = config(
+            &["stun:stun.example.com:3478"],
+            &["turn:turn.example.com:3478"],
+        );
+        let servers = ice_servers(&config, "cam001@studio.loc").unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].urls, vec!["stun:stun.example.com:3478"]);
+        assert!(servers[0].username.is_none() && servers[0].credential.is_none());
+        assert!(servers[1].username.is_some() && servers[1].credential.is_some());
+    }
+
+    #[test]
+    fn no_servers_configured() {
+        assert!(ice_servers(&config(&[], &[]), "cam001@studio.loc")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -7,17 +7,23 @@ use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::rt::net::TcpStream;
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
 
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
 use serde_derive::{Deserialize, Serialize};
 
 mod auth;
+mod bookmarks;
+mod ice;
 mod model;
 mod err;
 
-use model::Config;
+use bookmarks::{Bookmark, Bookmarks};
+use model::{ClientCerts, Config};
 use err::{Error, ChatError};
 
 // ---- Constants ----
@@ -100,6 +106,20 @@ struct RelayMessage {
     message: protocol::Message,
 }
 
+/// Peer flagged something on the timeline of a device
+#[derive(Message)]
+#[rtype(result = "()")]
+struct AddBookmark(Bookmark);
+
+/// List the bookmarks of a device, or of every device
+struct ListBookmarks {
+    device: Option<String>,
+}
+
+impl Message for ListBookmarks {
+    type Result = Vec<Bookmark>;
+}
+
 // ----- Server Implementation ----
 
 /// Client data the server needs to keep track of
@@ -122,17 +142,18 @@ impl ClientInfo {
 
 /// The server keeps track of all connected clients.  Clients are
 /// registered in the server when they hit the websocket endpoint.
-#[derive(Clone)]
 struct ChatServer {
     clients: HashMap<String, ClientInfo>,
+    bookmarks: Bookmarks,
     config: Config,
 }
 
 impl ChatServer {
-    fn new(config: Config) -> Self {
+    fn new(config: Config, bookmarks: Bookmarks) -> Self {
         debug!("New ProtocolServer created");
         Self {
             config,
+            bookmarks,
             clients: HashMap::new(),
         }
     }
@@ -235,7 +256,14 @@ impl Handler<AuthPeer> for ChatServer {
     /// Read authentication from AuthPeer, and if it is a valid
     /// credential, responds with a token
     fn handle(&mut self, msg: AuthPeer, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(match auth::create_token(&self.config, &msg.jid) {
+        let role = match auth::check_credential(&self.config, &msg.jid, &msg.password) {
+            Some(role) => role,
+            None => {
+                warn!("Wrong secret for {}", msg.jid);
+                return MessageResult(Ok(None));
+            }
+        };
+        MessageResult(match auth::create_token(&self.config, &msg.jid, role) {
             Ok(token) => Ok(Some(token)),
             Err(err) => Err(err),
         })
@@ -245,9 +273,14 @@ impl Handler<AuthPeer> for ChatServer {
 impl Handler<RelayMessage> for ChatServer {
     type Result = ();
 
-    /// Relay received message to a given client
+    /// Relay received message to a given client, or to all of them
+    /// when it isn't addressed to anyone
     fn handle(&mut self, msg: RelayMessage, _ctx: &mut Self::Context) {
         let message = serde_json::to_string(&msg).unwrap();
+        if msg.to_jid.is_empty() {
+            self.broadcast(ProtoMessage(message), Some(&msg.from_jid));
+            return;
+        }
         match self.clients.get(&msg.to_jid) {
             None => error!("Client `{}' not connected", msg.to_jid),
             Some(client) => client.addr.do_send(ProtoMessage(message)),
@@ -255,6 +288,39 @@ impl Handler<RelayMessage> for ChatServer {
     }
 }
 
+impl Handler<AddBookmark> for ChatServer {
+    type Result = ();
+
+    /// Keep the bookmark and send it to every other client, storage
+    /// included
+    fn handle(&mut self, msg: AddBookmark, _ctx: &mut Self::Context) {
+        let bookmark = msg.0;
+        info!("Bookmark on {} by {}: {}", bookmark.device, bookmark.from_jid, bookmark.label);
+        if let Err(err) = self.bookmarks.add(bookmark.clone()) {
+            error!("Can't save bookmark: {}", err);
+        }
+        let forward = protocol::Envelope {
+            from_jid: bookmark.from_jid.clone(),
+            to_jid: "".to_string(),
+            message: protocol::Message::Bookmark {
+                device: bookmark.device,
+                timestamp: bookmark.timestamp,
+                label: bookmark.label,
+            },
+        };
+        let forward_str = serde_json::to_string(&forward).unwrap();
+        self.broadcast(ProtoMessage(forward_str), Some(&bookmark.from_jid));
+    }
+}
+
+impl Handler<ListBookmarks> for ChatServer {
+    type Result = MessageResult<ListBookmarks>;
+
+    fn handle(&mut self, msg: ListBookmarks, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.bookmarks.list(msg.device.as_deref()))
+    }
+}
+
 // ---- Chat Connection implementation ----
 
 /// Each new client instantiates a ChatConnection.  The `jid'
@@ -311,19 +377,36 @@ impl ChatConnection {
         });
     }
 
+    /// Messages are always sent on behalf of the JID the connection
+    /// was authenticated as, whatever `from_jid' they carry
     fn _handle_message(&self, msg: &str) -> Result<(), serde_json::Error> {
         let deserialized: protocol::Envelope = serde_json::from_str(msg)?;
 
         match deserialized.message {
             protocol::Message::PeerCaps(capabilities) => {
                 self.server.do_send(Capabilities {
-                    jid: deserialized.from_jid,
+                    jid: self.jid.clone(),
                     capabilities,
                 });
             }
+            protocol::Message::Bookmark {
+                device,
+                timestamp,
+                label,
+            } => {
+                self.server.do_send(AddBookmark(Bookmark {
+                    from_jid: self.jid.clone(),
+                    device,
+                    timestamp,
+                    label,
+                }));
+            }
+            relay if deserialized.to_jid.is_empty() && !is_notification(&relay) => {
+                warn!("{} can't broadcast {:?}", self.jid, relay);
+            }
             relay => {
                 self.server.do_send(RelayMessage {
-                    from_jid: deserialized.from_jid,
+                    from_jid: self.jid.clone(),
                     to_jid: deserialized.to_jid,
                     message: relay,
                 });
@@ -343,6 +426,18 @@ impl ChatConnection {
     }
 }
 
+/// Messages that can go to every peer at once.  The others are only
+/// relayed to the peer they're addressed to.
+fn is_notification(message: &protocol::Message) -> bool {
+    matches!(
+        message,
+        protocol::Message::RecordingGap { .. }
+            | protocol::Message::MotionStart { .. }
+            | protocol::Message::MotionStop { .. }
+            | protocol::Message::EventClip { .. }
+    )
+}
+
 /// Define HTTP Actor for the ChatConnection struct
 impl Actor for ChatConnection {
     type Context = ws::WebsocketContext<Self>;
@@ -405,9 +500,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatConnection {
 /// List currently connected clients
 async fn http_api_peers(
     req: HttpRequest,
+    config: web::Data<Config>,
     server: web::Data<Addr<ChatServer>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    match read_jid_from_request(&req) {
+    match read_jid_from_request(&req, &config, config.userauth.allow_legacy) {
         None => Ok(HttpResponse::Unauthorized().finish()),
         Some(Err(_e)) => Ok(HttpResponse::BadRequest().finish()),
         Some(Ok(jid)) => {
@@ -422,6 +518,53 @@ async fn http_api_peers(
     }
 }
 
+#[derive(Deserialize)]
+struct QueryBookmarks {
+    device: Option<String>,
+}
+
+/// STUN and TURN servers for peers to use, with TURN credentials
+/// issued to clients with a token or a certificate.  Legacy JID-only
+/// tokens don't prove anything, so they don't get any.  Empty when
+/// `[ice]' isn't configured.
+async fn http_api_ice_servers(
+    req: HttpRequest,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, actix_web::Error> {
+    match read_jid_from_request(&req, &config, false) {
+        None => Ok(HttpResponse::Unauthorized().finish()),
+        Some(Err(_e)) => Ok(HttpResponse::BadRequest().finish()),
+        Some(Ok(jid)) => match &config.ice {
+            None => Ok(HttpResponse::Ok().json(Vec::<ice::IceServer>::new())),
+            Some(ice) => Ok(HttpResponse::Ok().json(ice::ice_servers(ice, &jid)?)),
+        },
+    }
+}
+
+/// List the bookmarks peers put on the timeline of the devices,
+/// oldest first
+async fn http_api_bookmarks(
+    req: HttpRequest,
+    query: web::Query<QueryBookmarks>,
+    config: web::Data<Config>,
+    server: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    match read_jid_from_request(&req, &config, config.userauth.allow_legacy) {
+        None => Ok(HttpResponse::Unauthorized().finish()),
+        Some(Err(_e)) => Ok(HttpResponse::BadRequest().finish()),
+        Some(Ok(_jid)) => {
+            let device = query.into_inner().device;
+            match server.send(ListBookmarks { device }).await {
+                Ok(bookmarks) => Ok(HttpResponse::Ok().json(bookmarks)),
+                Err(err) => {
+                    error!("{:?}", err);
+                    Ok(HttpResponse::InternalServerError().finish())
+                },
+            }
+        }
+    }
+}
+
 /// Represents the data that arrives from the authentication form, and
 /// that's shipped to the server actor to authenticate a peer
 #[derive(Debug, Deserialize)]
@@ -431,7 +574,7 @@ struct AuthPeer {
 }
 
 impl Message for AuthPeer {
-    type Result = Result<Option<String>, Error>;
+    type Result = Result<Option<(String, u64)>, Error>;
 }
 
 
@@ -439,6 +582,8 @@ impl Message for AuthPeer {
 #[derive(Debug, Serialize)]
 struct AuthResponse {
     token: String,
+    /// When the token expires, in seconds since the UNIX epoch
+    exp: u64,
 }
 
 /// Authenticate the user.  It takes the user from the request body
@@ -467,7 +612,7 @@ async fn auth(
         password: form.password.to_string(),
     }).await?? {
         None => HttpResponse::Unauthorized().finish(),
-        Some(token) => HttpResponse::Ok().json(AuthResponse { token }),
+        Some((token, exp)) => HttpResponse::Ok().json(AuthResponse { token, exp }),
     })
 }
 
@@ -483,9 +628,10 @@ async fn auth(
 async fn ws(
     req: HttpRequest,
     stream: web::Payload,
+    config: web::Data<Config>,
     server: web::Data<Addr<ChatServer>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    match read_jid_from_request(&req) {
+    match read_jid_from_request(&req, &config, config.userauth.allow_legacy) {
         None => Ok(HttpResponse::Unauthorized().finish()),
         Some(Err(_e)) => Ok(HttpResponse::BadRequest().finish()),
         Some(Ok(jid)) => {
@@ -511,22 +657,57 @@ fn get_auth_header(req: &HttpRequest) -> Option<&str> {
     req.headers().get("Authorization")?.to_str().ok()
 }
 
-/// Parse token from within auth header and extract
-///
-/// TODO: Right now, this function returns a string with the JID
-/// itself instead returning the struct with the claims and stuff.
-/// Mostly because we actually don't have tokens yet.
-fn decode_token_from_header(authorization: &str) -> Result<String, Error> {
-    let decoded = base64::decode(&authorization[7..])?;
-    Ok(std::str::from_utf8(&decoded)?.to_string())
-}
-
-/// Decode & Check JWT token from HTTP header or QueryString
-fn read_jid_from_request(req: &HttpRequest) -> Option<Result<String, Error>> {
-    if let Ok(token) = get_auth_token(req) {
-        Some(Ok(token))
+/// Take the JID out of a token.  When `legacy' is set, tokens that
+/// aren't JWTs are the JID itself, base64 encoded when they come in
+/// the header, which is only accepted for JIDs that don't have a
+/// secret.
+fn decode_token(config: &Config, token: &str, encoded: bool, legacy: bool) -> Result<String, Error> {
+    if let Ok(jid) = auth::decode_token(config, token) {
+        return Ok(jid);
+    }
+    if !legacy {
+        return Err(Error::new());
+    }
+    let jid = if encoded {
+        std::str::from_utf8(&base64::decode(token)?)?.to_string()
     } else {
-        get_auth_header(req).map(decode_token_from_header)
+        token.to_string()
+    };
+    if auth::has_credential(config, &jid) {
+        warn!("{} tried to get in without a token", jid);
+        return Err(Error::new());
+    }
+    Ok(jid)
+}
+
+/// JID taken from the certificate a client presented, once verified
+#[derive(Clone, Debug)]
+struct CertJid(String);
+
+/// Decode & Check JWT token from HTTP header or QueryString.  Clients
+/// with a certificate are who it says they are, and don't need a
+/// token, but the ones that send one anyway can't claim to be someone
+/// else with it.  Legacy JID-only tokens are only taken with `legacy'.
+fn read_jid_from_request(
+    req: &HttpRequest,
+    config: &Config,
+    legacy: bool,
+) -> Option<Result<String, Error>> {
+    let from_token = if let Ok(token) = get_auth_token(req) {
+        Some(decode_token(config, &token, false, legacy))
+    } else {
+        get_auth_header(req).map(|header| {
+            let token = header.strip_prefix("Bearer ").ok_or_else(Error::new)?;
+            decode_token(config, token, true, legacy)
+        })
+    };
+    match (req.conn_data::<CertJid>(), from_token) {
+        (Some(CertJid(jid)), Some(Ok(other))) if other != *jid => {
+            warn!("{} presented the token of {}", jid, other);
+            Some(Err(Error::new()))
+        }
+        (Some(CertJid(jid)), _) => Some(Ok(jid.clone())),
+        (None, from_token) => from_token,
     }
 }
 
@@ -559,9 +740,22 @@ async fn main() -> Result<(), io::Error> {
     builder.set_private_key_file(&config.http.key, SslFiletype::PEM)?;
     builder.set_certificate_chain_file(&config.http.cert)?;
     builder.set_ca_file(&config.http.cacert)?;
+    match config.http.client_certs {
+        ClientCerts::Off => {}
+        ClientCerts::Optional => builder.set_verify(SslVerifyMode::PEER),
+        ClientCerts::Required => {
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT)
+        }
+    }
+    if config.http.client_certs != ClientCerts::Off {
+        builder.set_client_ca_list(X509Name::load_client_ca_file(&config.http.cacert)?);
+    }
+
+    // Bookmarks saved by previous runs
+    let bookmarks = Bookmarks::open(&config)?;
 
     // Address for the server actor
-    let server_actor = ChatServer::new(config.clone()).start();
+    let server_actor = ChatServer::new(config.clone(), bookmarks).start();
 
     // Spin it all up
     let app = move || {
@@ -572,8 +766,18 @@ async fn main() -> Result<(), io::Error> {
             .route("/ws", web::get().to(ws))
             .route("/auth", web::post().to(auth))
             .route("/peers", web::get().to(http_api_peers))
+            .route("/bookmarks", web::get().to(http_api_bookmarks))
+            .route("/ice-servers", web::get().to(http_api_ice_servers))
     };
     HttpServer::new(app)
+        .on_connect(|conn, data| {
+            let cert = conn
+                .downcast_ref::<TlsStream<TcpStream>>()
+                .and_then(|tls| tls.ssl().peer_certificate());
+            if let Some(jid) = cert.as_deref().and_then(auth::jid_from_certificate) {
+                data.insert(CertJid(jid));
+            }
+        })
         .bind_openssl(bind_addr, builder)?
         .run()
         .await
@@ -1,9 +1,46 @@
-use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct ConfigUserAuth {
     pub(crate) token_validity: u64,
     pub(crate) jwt_secret: String,
+    /// Let clients without a credential in with their bare JID as the
+    /// token, like before there were tokens
+    #[serde(default)]
+    pub(crate) allow_legacy: bool,
+}
+
+/// What the holder of a token is allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// Pushes its streams and chunks to storage
+    Device,
+    /// Watches live streams and recordings
+    Viewer,
+    /// Storage itself, listening for notifications
+    Storage,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigCredential {
+    /// Hex encoded SHA-256 of the secret
+    pub(crate) secret: String,
+    pub(crate) role: Role,
+}
+
+/// Whether clients are asked for certificates signed by `cacert'
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClientCerts {
+    #[default]
+    Off,
+    /// Clients that present one have it verified
+    Optional,
+    /// Clients that don't present one can't connect
+    Required,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -13,10 +50,44 @@ pub(crate) struct ConfigHTTP {
     pub(crate) key: String,
     pub(crate) cert: String,
     pub(crate) cacert: String,
+    #[serde(default)]
+    pub(crate) client_certs: ClientCerts,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigBookmarks {
+    /// File bookmarks are appended to
+    pub(crate) path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigICE {
+    /// `stun:host:port'
+    #[serde(default)]
+    pub(crate) stun_servers: Vec<String>,
+    /// `turn:host:port', or `turns:' for TLS, with `?transport=tcp'
+    /// to relay over TCP
+    #[serde(default)]
+    pub(crate) turn_servers: Vec<String>,
+    /// Shared with the TURN servers, which check the credentials
+    /// handed out here with it
+    pub(crate) turn_secret: String,
+    /// Seconds the TURN credentials are valid for
+    #[serde(default = "default_turn_ttl")]
+    pub(crate) turn_ttl: u64,
+}
+
+fn default_turn_ttl() -> u64 {
+    86400
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct Config {
     pub(crate) http: ConfigHTTP,
     pub(crate) userauth: ConfigUserAuth,
+    pub(crate) bookmarks: Option<ConfigBookmarks>,
+    pub(crate) ice: Option<ConfigICE>,
+    /// Secret and role of each client that can get a token
+    #[serde(default)]
+    pub(crate) credentials: HashMap<String, ConfigCredential>,
 }
@@ -0,0 +1,1152 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use actix_files::NamedFile;
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use gst_rtsp_server::RTSPMountPoints;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::catalog::{self, Chunk, ChunkState, SharedCatalog, Tier};
+use crate::crypto::{self, MasterKey, PlainFile};
+use crate::events::{Event, SharedEventLog};
+use crate::model::Config;
+use crate::{archive, auth, gaps, play, playback, record, thumbnail};
+
+/// Header that carries the hex encoded SHA-256 of an uploaded chunk
+pub(crate) const CHECKSUM_HEADER: &str = "X-Checksum-Sha256";
+
+/// Largest chunk accepted by the upload endpoints.  Uploads are
+/// written to disk as they come, so it doesn't bound memory.
+const MAX_UPLOAD_SIZE: usize = 512 * 1024 * 1024;
+
+/// Longest time range a single export can cover, in milliseconds.
+/// Everything in it is fetched, and decrypted in memory, at once.
+const MAX_EXPORT_SPAN: u64 = 60 * 60 * 1000;
+
+/// Start the HTTP API in its own thread, since the main thread is
+/// taken by the GLib main loop that drives the RTSP server.
+pub(crate) fn spawn(
+    config: Config,
+    catalog: SharedCatalog,
+    events: SharedEventLog,
+    mounts: RTSPMountPoints,
+) -> thread::JoinHandle<std::io::Result<()>> {
+    thread::spawn(move || actix_rt::System::new().block_on(serve(config, catalog, events, mounts)))
+}
+
+async fn serve(
+    config: Config,
+    catalog: SharedCatalog,
+    events: SharedEventLog,
+    mounts: RTSPMountPoints,
+) -> std::io::Result<()> {
+    let bind_addr = format!("{}:{}", config.api.host, config.api.port);
+    info!("HTTP API listening at {}", bind_addr);
+    let playbacks = play::Playbacks::new(mounts);
+
+    let app = move || {
+        App::new()
+            .wrap(middleware::Logger::default())
+            .app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(catalog.clone()))
+            .app_data(web::Data::new(events.clone()))
+            .app_data(web::Data::new(playbacks.clone()))
+            .route("/status", web::get().to(status))
+            .route("/upload/{jid}/{name}", web::put().to(upload))
+            .route("/chunks/{jid}", web::get().to(list_chunks))
+            .route("/chunks/{jid}/{name}", web::get().to(download_chunk))
+            .route("/chunks/{jid}/{name}/pin", web::put().to(pin_chunk))
+            .route("/chunks/{jid}/{name}/pin", web::delete().to(unpin_chunk))
+            .route("/export/{jid}", web::get().to(export))
+            .route("/hls/{jid}/index.m3u8", web::get().to(hls_playlist))
+            .route("/hls/{jid}/{start}.ts", web::get().to(hls_segment))
+            .route("/rtsp/{jid}", web::post().to(rtsp_playback))
+            .route("/thumbnails/{jid}", web::get().to(list_thumbnails))
+            .route(
+                "/thumbnails/{jid}/{timestamp}.jpg",
+                web::get().to(get_thumbnail),
+            )
+            .route("/sprite/{jid}", web::get().to(sprite))
+            .route("/gaps/{jid}", web::get().to(list_gaps))
+            .route("/events/{jid}", web::get().to(list_events))
+            .route("/metrics", web::get().to(metrics))
+            .route("/replica/{jid}/{name}", web::put().to(replica_chunk))
+            .route(
+                "/replica/{jid}/{name}/record",
+                web::put().to(replica_record),
+            )
+    };
+    HttpServer::new(app).bind(bind_addr)?.run().await
+}
+
+/// Used by capture devices to find out if storage is reachable
+/// before shipping anything to it.
+async fn status() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+/// Receive a chunk a capture device recorded while it couldn't reach
+/// storage, or a chunk of an event clip.  The body is the chunk
+/// itself and the header `X-Checksum-Sha256' must carry its
+/// checksum.  Uploading the same chunk twice succeeds, so devices
+/// can resume backfilling after being interrupted without keeping
+/// track of what was received.
+async fn upload(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Payload,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+    events: web::Data<SharedEventLog>,
+) -> HttpResponse {
+    let (jid, name) = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    if !auth::is_authorized(&config, &jid, bearer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if record::parse_chunk_name(&name).is_none() {
+        return HttpResponse::BadRequest().body("Invalid chunk name");
+    }
+    let expected = match req
+        .headers()
+        .get(CHECKSUM_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        None => return HttpResponse::BadRequest().body("Missing checksum"),
+        Some(value) => value.to_lowercase(),
+    };
+    let master = match crypto::master_key(&config) {
+        Ok(master) => master,
+        Err(err) => {
+            error!("Can't encrypt chunk {} from {}: {}", name, jid, err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let dest = config.device_path(&jid).join(&name);
+    let partial = dest.with_extension("part");
+    let checksum = match receive_chunk(body, partial.clone(), master.clone()).await {
+        Ok(checksum) => checksum,
+        Err(err) => return refused(&jid, &name, err),
+    };
+    if checksum != expected {
+        warn!("Checksum mismatch on chunk {} from {}", name, jid);
+        let _ = std::fs::remove_file(&partial);
+        return HttpResponse::UnprocessableEntity().body("Checksum mismatch");
+    }
+
+    let device = jid.clone();
+    let catalog = catalog.get_ref().clone();
+    let events = events.get_ref().clone();
+    let config = config.get_ref().clone();
+    let stored = web::block(move || {
+        let created = store_chunk(&dest, &partial, &expected, master.as_ref())?;
+        if created {
+            let mut chunk =
+                catalog::probe_chunk(&config, &device, &dest).map_err(StoreError::Catalog)?;
+            // Clips and bookmarks get reported before they're uploaded
+            let end = chunk.end.unwrap_or(chunk.start) + 1;
+            for event in events.lock().unwrap().range(&device, chunk.start, end) {
+                if event.is_bookmark() {
+                    chunk.pinned = true;
+                } else {
+                    chunk.event = true;
+                }
+            }
+            catalog
+                .lock()
+                .unwrap()
+                .put(chunk)
+                .map_err(StoreError::Catalog)?;
+        }
+        Ok(created)
+    });
+    match stored.await {
+        Ok(Ok(true)) => {
+            info!("Backfilled chunk {} from {}", name, jid);
+            HttpResponse::Created().finish()
+        }
+        Ok(Ok(false)) => HttpResponse::Ok().finish(),
+        Ok(Err(err)) => refused(&jid, &name, err),
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Token a viewer shows, either as a bearer token or in the query of
+/// the URL as in `?token=<TOKEN>', for what can't set headers, like
+/// `<video>' tags and HLS players
+fn viewer_token(req: &HttpRequest) -> Option<&str> {
+    bearer_token(req).or_else(|| {
+        req.query_string()
+            .split('&')
+            .find_map(|p| p.strip_prefix("token="))
+    })
+}
+
+/// Query that passes the token of a viewer along to the URLs handed
+/// back to them
+fn token_query(req: &HttpRequest) -> String {
+    viewer_token(req).map_or_else(String::new, |token| format!("?token={}", token))
+}
+
+/// Whether a request comes from a peer allowed to replicate its
+/// chunks to this storage
+fn is_peer(req: &HttpRequest, config: &Config) -> bool {
+    let replica = match &config.replica {
+        Some(replica) => replica,
+        None => return false,
+    };
+    match bearer_token(req) {
+        Some(token) => {
+            token.len() == replica.token.len()
+                && openssl::memcmp::eq(token.as_bytes(), replica.token.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// Receive a chunk replicated from a peer, exactly as the peer has it
+/// on disk.  Its record comes right after.
+async fn replica_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Payload,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !is_peer(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (jid, name) = path.into_inner();
+    if !config.is_device(&jid) || record::parse_chunk_name(&name).is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+    let expected = match req
+        .headers()
+        .get(CHECKSUM_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        None => return HttpResponse::BadRequest().body("Missing checksum"),
+        Some(value) => value.to_lowercase(),
+    };
+    // Replicas come as they are on the peer's disk, so they're stored
+    // as they come
+    let dest = config.device_path(&jid).join(&name);
+    let partial = dest.with_extension("part");
+    let checksum = match receive_chunk(body, partial.clone(), None).await {
+        Ok(checksum) => checksum,
+        Err(err) => return refused(&jid, &name, err),
+    };
+    if checksum != expected {
+        warn!("Checksum mismatch on replica of {}/{}", jid, name);
+        let _ = std::fs::remove_file(&partial);
+        return HttpResponse::UnprocessableEntity().body("Checksum mismatch");
+    }
+
+    match web::block(move || store_chunk(&dest, &partial, &expected, None)).await {
+        Ok(Ok(true)) => HttpResponse::Created().finish(),
+        Ok(Ok(false)) => HttpResponse::Ok().finish(),
+        Ok(Err(err)) => refused(&jid, &name, err),
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Receive the catalog record of a chunk replicated from a peer.  The
+/// chunk itself has to be here already, unless it was quarantined or
+/// deleted over there, and the record has to link to the chain of
+/// its device here.
+async fn replica_record(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    chunk: web::Json<Chunk>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !is_peer(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (jid, name) = path.into_inner();
+    let mut chunk = chunk.into_inner();
+    if !config.is_device(&jid) || chunk.device != jid || chunk.name != name {
+        return HttpResponse::NotFound().finish();
+    }
+    let stored = match chunk.state {
+        ChunkState::Complete | ChunkState::Repaired => true,
+        _ => false,
+    };
+    if stored && !config.device_path(&jid).join(&name).exists() {
+        return HttpResponse::Conflict().body("Chunk missing");
+    }
+    chunk.replicated = None;
+    chunk.tier = Tier::Local;
+    let mut catalog = catalog.lock().unwrap();
+    match catalog.links(&chunk) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Replica of {}/{} doesn't link to the chain here", jid, name);
+            return HttpResponse::Conflict().body("Record doesn't link");
+        }
+        Err(err) => {
+            error!("Can't check replica of {}/{}: {}", jid, name, err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    match catalog.put(chunk) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            error!("Can't catalog replica of {}/{}: {}", jid, name, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Interval of wall-clock time, in milliseconds since the UNIX
+/// epoch.  Both ends are optional.
+#[derive(Debug, Deserialize)]
+struct TimeRange {
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+impl TimeRange {
+    fn bounds(&self) -> (u64, u64) {
+        (self.start.unwrap_or(0), self.end.unwrap_or(u64::MAX))
+    }
+}
+
+/// Finished chunks of a device within a time range, oldest first
+fn finished_chunks(catalog: &SharedCatalog, jid: &str, range: &TimeRange) -> Vec<Chunk> {
+    let (start, end) = range.bounds();
+    catalog
+        .lock()
+        .unwrap()
+        .range(jid, start, end)
+        .into_iter()
+        .filter(|chunk| chunk.end.is_some())
+        .cloned()
+        .collect()
+}
+
+/// List the chunks of a device within a time range, including the
+/// one being written, if any
+async fn list_chunks(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let (start, end) = range.bounds();
+    let chunks = catalog
+        .lock()
+        .unwrap()
+        .range(&jid, start, end)
+        .into_iter()
+        .cloned()
+        .collect::<Vec<Chunk>>();
+    HttpResponse::Ok().json(chunks)
+}
+
+/// Download a single chunk, decrypted if needed and fetched from the
+/// archive if it's no longer on disk.  Range requests are supported,
+/// so the URL can be handed straight to a `<video>' tag.
+async fn download_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let (jid, name) = path.into_inner();
+    let start = match record::parse_chunk_name(&name) {
+        Some(start) if config.is_device(&jid) => start,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let chunk = match catalog.lock().unwrap().get(&jid, start) {
+        Some(chunk) if chunk.end.is_some() => chunk.clone(),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let config = config.get_ref().clone();
+    let file_name = chunk.name.clone();
+    let opened = match archive::fetch(&config, &chunk).await {
+        Ok(fetched) => {
+            web::block(move || -> Result<NamedFile, failure::Error> {
+                // What was opened can still be read once the plain
+                // copy is gone.  It's named after the chunk, which
+                // gives it its content type.
+                let plain = fetched.into_plain(&config)?;
+                let file = std::fs::File::open(plain.path())?;
+                Ok(NamedFile::from_file(file, &file_name)?)
+            })
+            .await
+        }
+        Err(err) => Ok(Err(err)),
+    };
+    match opened {
+        Ok(Ok(file)) => Ok(file.into_response(&req)),
+        Ok(Err(err)) => {
+            error!("Can't read chunk {}/{}: {}", jid, name, err);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Keep a chunk from being deleted by the retention policies
+async fn pin_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    set_pinned(&catalog, path.into_inner(), true)
+}
+
+/// Let the retention policies delete a chunk again
+async fn unpin_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    set_pinned(&catalog, path.into_inner(), false)
+}
+
+fn set_pinned(
+    catalog: &SharedCatalog,
+    (jid, name): (String, String),
+    pinned: bool,
+) -> HttpResponse {
+    let start = match record::parse_chunk_name(&name) {
+        Some(start) => start,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let mut catalog = catalog.lock().unwrap();
+    let mut chunk = match catalog.get(&jid, start) {
+        Some(chunk) => chunk.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    chunk.pinned = pinned;
+    match catalog.put(chunk.clone()) {
+        Ok(()) => HttpResponse::Ok().json(chunk),
+        Err(err) => {
+            error!("Can't pin chunk {} of {}: {}", name, jid, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Export the chunks of a device within a time range as a single MP4
+/// file.  The media isn't encoded again, so the file starts and ends
+/// at the boundaries of the chunks overlapping the range.  The range
+/// needs both ends, and can't be longer than `MAX_EXPORT_SPAN'.
+async fn export(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    match (range.start, range.end) {
+        (Some(start), Some(end)) if start < end && end - start <= MAX_EXPORT_SPAN => {}
+        _ => {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "Both start and end are needed, at most {} ms apart",
+                MAX_EXPORT_SPAN
+            )))
+        }
+    }
+    let chunks = finished_chunks(&catalog, &jid, &range);
+    let (first, last) = match (chunks.first(), chunks.last()) {
+        (Some(first), Some(last)) => (first.start, last.end.unwrap_or(last.start)),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let mut fetched = Vec::with_capacity(chunks.len());
+    for chunk in &chunks {
+        match archive::fetch(&config, chunk).await {
+            Ok(file) => fetched.push(file),
+            Err(err) => {
+                error!("Can't export {}: {}", jid, err);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+
+    let file_name = format!("{}-{}-{}.mp4", jid, first, last);
+    let name = file_name.clone();
+    let config = config.get_ref().clone();
+    let exported = web::block(move || -> Result<NamedFile, failure::Error> {
+        let dest = PlainFile::scratch(&config, &name)?;
+        let plain = fetched
+            .into_iter()
+            .map(|file| file.into_plain(&config))
+            .collect::<Result<Vec<_>, _>>()?;
+        let files = plain
+            .iter()
+            .map(|file| file.path().to_path_buf())
+            .collect::<Vec<PathBuf>>();
+        // Putting the headers first would take a temporary file the
+        // export can't be kept out of
+        playback::export(&files, dest.path(), config.encryption.is_none())?;
+        // What was opened can still be read once the export is gone
+        let file = std::fs::File::open(dest.path())?;
+        Ok(NamedFile::from_file(file, &name)?)
+    })
+    .await;
+
+    match exported {
+        Ok(Ok(file)) => Ok(file
+            .set_content_disposition(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(file_name)],
+            })
+            .into_response(&req)),
+        Ok(Err(err)) => {
+            error!("Can't export {}: {}", jid, err);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// HLS playlist with the chunks of a device within a time range, so
+/// history can be played with a plain `<video>' tag
+async fn hls_playlist(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let chunks = finished_chunks(&catalog, &jid, &range);
+    HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .body(playback::hls_playlist(
+            &chunks.iter().collect::<Vec<_>>(),
+            &token_query(&req),
+        ))
+}
+
+/// Segment of an HLS playlist.  Chunks are remuxed into MPEG-TS the
+/// first time they're asked for, and kept around until storage
+/// restarts.  Segments of encrypted chunks and of chunks fetched from
+/// the archive are never kept around.
+async fn hls_segment(
+    req: HttpRequest,
+    path: web::Path<(String, u64)>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (jid, start) = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let chunk = match catalog.lock().unwrap().get(&jid, start) {
+        Some(chunk) if chunk.end.is_some() => chunk.clone(),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let dest = config
+        .tmp_path()
+        .join("hls")
+        .join(&jid)
+        .join(format!("{}.ts", start));
+
+    let config = config.get_ref().clone();
+    let fetched = if dest.exists() {
+        None
+    } else {
+        match archive::fetch(&config, &chunk).await {
+            Ok(fetched) => Some(fetched),
+            Err(err) => {
+                error!("Can't fetch chunk {}/{}: {}", jid, chunk.name, err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    };
+    let segment = web::block(move || -> Result<Vec<u8>, failure::Error> {
+        if let Some(fetched) = fetched {
+            let plain = fetched.into_plain(&config)?;
+            if plain.is_temporary() {
+                let scratch = PlainFile::scratch(&config, &format!("{}.ts", start))?;
+                playback::remux_ts(plain.path(), scratch.path())?;
+                return Ok(std::fs::read(scratch.path())?);
+            }
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            let partial = dest.with_extension("part");
+            playback::remux_ts(plain.path(), &partial)?;
+            std::fs::rename(&partial, &dest)?;
+        }
+        Ok(std::fs::read(&dest)?)
+    })
+    .await;
+
+    match segment {
+        Ok(Ok(data)) => HttpResponse::Ok().content_type("video/mp2t").body(data),
+        Ok(Err(err)) => {
+            error!("Can't remux chunk {}/{}: {}", jid, chunk.name, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Shape of each entry returned by the thumbnail listing
+#[derive(Debug, Serialize)]
+struct Thumbnail {
+    timestamp: u64,
+    url: String,
+}
+
+/// List the thumbnails of a device within a time range
+async fn list_thumbnails(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    let query = token_query(&req);
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let (start, end) = range.bounds();
+    let device = jid.clone();
+    let config = config.get_ref().clone();
+    match web::block(move || thumbnail::list(&config, &device, start, end)).await {
+        Ok(Ok(timestamps)) => HttpResponse::Ok().json(
+            timestamps
+                .into_iter()
+                .map(|timestamp| Thumbnail {
+                    timestamp,
+                    url: format!("/thumbnails/{}/{}.jpg{}", jid, timestamp, query),
+                })
+                .collect::<Vec<Thumbnail>>(),
+        ),
+        Ok(Err(err)) => {
+            error!("Can't list thumbnails of {}: {}", jid, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn get_thumbnail(
+    req: HttpRequest,
+    path: web::Path<(String, u64)>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (jid, timestamp) = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let config = config.get_ref().clone();
+    match web::block(move || thumbnail::read(&config, &jid, timestamp)).await {
+        Ok(Ok(data)) => HttpResponse::Ok().content_type("image/jpeg").body(data),
+        Ok(Err(_)) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Layout of a sprite sheet
+#[derive(Debug, Deserialize)]
+struct SpriteQuery {
+    start: Option<u64>,
+    end: Option<u64>,
+    /// Thumbnails per row
+    columns: Option<u32>,
+    /// Most thumbnails in the sheet, they're picked evenly across the
+    /// range when there are more
+    max: Option<usize>,
+}
+
+/// Header that lists the timestamps of the thumbnails in a sprite
+/// sheet, left to right and top to bottom
+const SPRITE_HEADER: &str = "X-Sprite-Timestamps";
+
+/// Thumbnails of a device within a time range in a single JPEG
+async fn sprite(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<SpriteQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let range = TimeRange {
+        start: query.start,
+        end: query.end,
+    };
+    let (start, end) = range.bounds();
+    let columns = query.columns.unwrap_or(10);
+    let max = query.max.unwrap_or(100).max(1);
+
+    let device = jid.clone();
+    let config = config.get_ref().clone();
+    let sheet = web::block(
+        move || -> Result<Option<(Vec<u64>, Vec<u8>)>, failure::Error> {
+            let all = thumbnail::list(&config, &device, start, end)?;
+            if all.is_empty() {
+                return Ok(None);
+            }
+            let step = (all.len() + max - 1) / max;
+            let picked = all.into_iter().step_by(step).collect::<Vec<u64>>();
+            let data = thumbnail::sprite(&config, &device, &picked, columns)?;
+            Ok(Some((picked, data)))
+        },
+    )
+    .await;
+
+    match sheet {
+        Ok(Ok(Some((timestamps, data)))) => HttpResponse::Ok()
+            .content_type("image/jpeg")
+            .insert_header((
+                SPRITE_HEADER,
+                timestamps
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ))
+            .body(data),
+        Ok(Ok(None)) => HttpResponse::NotFound().finish(),
+        Ok(Err(err)) => {
+            error!("Can't make sprite of {}: {}", jid, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Gaps in the timeline of a device overlapping a time range, oldest
+/// first.  The last one has no end if the device isn't recording.
+async fn list_gaps(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let (start, end) = range.bounds();
+    let now = record::now_ms();
+    let found = gaps::find(&config, &catalog.lock().unwrap(), &jid, now)
+        .into_iter()
+        .filter(|gap| gap.start < end && gap.end.unwrap_or(now) > start)
+        .collect::<Vec<gaps::Gap>>();
+    HttpResponse::Ok().json(found)
+}
+
+#[derive(Debug, Deserialize)]
+struct EventQuery {
+    start: Option<u64>,
+    end: Option<u64>,
+    /// Only events with this trigger, like `bookmark'
+    trigger: Option<String>,
+    /// Only events whose label has this text, ignoring case
+    q: Option<String>,
+}
+
+/// Event clips recorded by a device and bookmarks put on its
+/// timeline overlapping a time range, oldest first
+async fn list_events(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<EventQuery>,
+    config: web::Data<Config>,
+    events: web::Data<SharedEventLog>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let range = TimeRange {
+        start: query.start,
+        end: query.end,
+    };
+    let (start, end) = range.bounds();
+    let text = query.q.as_ref().map(|q| q.to_lowercase());
+    let found = events
+        .lock()
+        .unwrap()
+        .range(&jid, start, end)
+        .into_iter()
+        .filter(|e| query.trigger.as_ref().map_or(true, |t| e.trigger == *t))
+        .filter(|e| match (&text, &e.label) {
+            (Some(text), Some(label)) => label.to_lowercase().contains(text),
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .cloned()
+        .collect::<Vec<Event>>();
+    HttpResponse::Ok().json(found)
+}
+
+/// Whether a request may scrape the metrics: with `metrics.token'
+/// as its bearer token when set, as a viewer otherwise
+fn is_scraper(req: &HttpRequest, config: &Config) -> bool {
+    let metrics = match &config.metrics {
+        Some(metrics) => metrics,
+        None => return auth::is_viewer(config, viewer_token(req)),
+    };
+    match bearer_token(req) {
+        Some(token) => {
+            token.len() == metrics.token.len()
+                && openssl::memcmp::eq(token.as_bytes(), metrics.token.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// Metrics about the continuity of the recordings, to be scraped by
+/// Prometheus
+async fn metrics(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+) -> HttpResponse {
+    if !is_scraper(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let text = gaps::metrics(&config, &catalog.lock().unwrap(), record::now_ms());
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(text)
+}
+
+/// Shape of the data returned by the RTSP playback endpoint
+#[derive(Debug, Serialize)]
+struct RTSPPlayback {
+    url: String,
+}
+
+/// Mount the recordings of a device within a time range on the RTSP
+/// server, and tell where they can be played from.  The range must
+/// have an end, so asking for it again finds the same mount point.
+async fn rtsp_playback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    range: web::Query<TimeRange>,
+    config: web::Data<Config>,
+    catalog: web::Data<SharedCatalog>,
+    playbacks: web::Data<play::Playbacks>,
+) -> HttpResponse {
+    if !auth::is_viewer(&config, viewer_token(&req)) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let jid = path.into_inner();
+    if !config.is_device(&jid) {
+        return HttpResponse::NotFound().finish();
+    }
+    let end = match range.end {
+        Some(end) => end,
+        None => return HttpResponse::BadRequest().body("Missing end"),
+    };
+    let start = range.start.unwrap_or(0);
+    let path = play::playback_path(&jid, start, end);
+    if !playbacks.is_mounted(&path) {
+        let chunks = finished_chunks(&catalog, &jid, &range);
+        if chunks.is_empty() {
+            return HttpResponse::NotFound().finish();
+        }
+        match fetch_plain(&config, chunks).await {
+            Ok(files) => playbacks.mount(&jid, start, end, files),
+            Err(err) => {
+                error!("Can't mount recordings of {}: {}", jid, err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    // The RTSP server is reached on the same host as the API
+    let info = req.connection_info();
+    HttpResponse::Ok().json(RTSPPlayback {
+        url: format!(
+            "rtsp://{}:{}{}{}",
+            host_name(info.host()),
+            config.http.port,
+            path,
+            token_query(&req),
+        ),
+    })
+}
+
+/// Get hold of chunks as plain media, downloading the archived ones
+/// and decrypting the encrypted ones away from the event loop
+async fn fetch_plain(
+    config: &Config,
+    chunks: Vec<Chunk>,
+) -> Result<Vec<PlainFile>, failure::Error> {
+    let mut fetched = Vec::with_capacity(chunks.len());
+    for chunk in &chunks {
+        fetched.push(archive::fetch(config, chunk).await?);
+    }
+    let config = config.clone();
+    web::block(move || {
+        fetched
+            .into_iter()
+            .map(|file| file.into_plain(&config))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await?
+}
+
+/// Host of a `Host' header without its port, IPv6 addresses, which
+/// come in brackets, included
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+enum StoreError {
+    /// A different chunk with the same name already exists
+    Conflict,
+    /// The upload went over `MAX_UPLOAD_SIZE'
+    TooLarge,
+    /// The upload broke off before it was over
+    Payload(String),
+    IO(std::io::Error),
+    Catalog(failure::Error),
+    Crypto(failure::Error),
+}
+
+/// Answer to an upload, or a replica, that couldn't be stored
+fn refused(jid: &str, name: &str, err: StoreError) -> HttpResponse {
+    match err {
+        StoreError::Conflict => HttpResponse::Conflict().finish(),
+        StoreError::TooLarge => HttpResponse::PayloadTooLarge().finish(),
+        StoreError::Payload(err) => {
+            warn!("Upload of {}/{} broke off: {}", jid, name, err);
+            HttpResponse::BadRequest().finish()
+        }
+        StoreError::IO(err) => {
+            error!("Can't store chunk {}/{}: {}", jid, name, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        StoreError::Catalog(err) => {
+            error!("Can't catalog chunk {}/{}: {}", jid, name, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        StoreError::Crypto(err) => {
+            error!("Can't encrypt chunk {}/{}: {}", jid, name, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        StoreError::IO(error)
+    }
+}
+
+/// Stream the body of an upload into `partial', encrypted as it's
+/// written given the master key, and hand back the hex encoded
+/// SHA-256 of what was received.  The file is written in a blocking
+/// thread a few pieces behind the body, so only those are held in
+/// memory.  Nothing is left behind when it fails.
+async fn receive_chunk(
+    mut body: web::Payload,
+    partial: PathBuf,
+    master: Option<MasterKey>,
+) -> Result<String, StoreError> {
+    let (mut pieces, mut received) = mpsc::channel::<web::Bytes>(4);
+    let dest = partial.clone();
+    let writing = web::block(move || -> Result<String, StoreError> {
+        if let Some(dir) = dest.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(&dest)?;
+        let mut hasher = Sha256::new();
+        let file = match &master {
+            Some(master) => {
+                let writer = crypto::ChunkWriter::new(master, file).map_err(StoreError::Crypto)?;
+                // Records as large as they go, rather than one for
+                // each piece of the body
+                let mut writer = BufWriter::with_capacity(crypto::RECORD_SIZE, writer);
+                write_pieces(&mut received, &mut hasher, &mut writer)?;
+                let writer = writer.into_inner().map_err(|e| e.into_error())?;
+                writer.finish().map_err(StoreError::Crypto)?
+            }
+            None => {
+                let mut file = file;
+                write_pieces(&mut received, &mut hasher, &mut file)?;
+                file
+            }
+        };
+        file.sync_all()?;
+        Ok(hex::encode(hasher.finalize()))
+    });
+
+    let mut size = 0;
+    let mut result = Ok(());
+    while let Some(piece) = body.next().await {
+        let piece = match piece {
+            Ok(piece) => piece,
+            Err(err) => {
+                result = Err(StoreError::Payload(err.to_string()));
+                break;
+            }
+        };
+        size += piece.len();
+        if size > MAX_UPLOAD_SIZE {
+            result = Err(StoreError::TooLarge);
+            break;
+        }
+        // The writer only hangs up when it failed, which it tells
+        // below
+        if pieces.send(piece).await.is_err() {
+            break;
+        }
+    }
+    drop(pieces);
+
+    let written = match writing.await {
+        Ok(written) => written,
+        Err(err) => Err(StoreError::IO(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            err.to_string(),
+        ))),
+    };
+    let checksum = result.and(written);
+    if checksum.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    checksum
+}
+
+/// Hash and write the pieces of a body as they come
+fn write_pieces<W: Write>(
+    received: &mut mpsc::Receiver<web::Bytes>,
+    hasher: &mut Sha256,
+    dest: &mut W,
+) -> std::io::Result<()> {
+    while let Some(piece) = futures::executor::block_on(received.next()) {
+        hasher.update(&piece);
+        dest.write_all(&piece)?;
+    }
+    Ok(())
+}
+
+/// Move a received chunk from `partial' into place, so a half written
+/// upload never looks like a chunk.  Returns `false`, and drops what
+/// was received, if the very same chunk was already there, as is or,
+/// given the master key, once decrypted.
+fn store_chunk(
+    dest: &Path,
+    partial: &Path,
+    checksum: &str,
+    master: Option<&MasterKey>,
+) -> Result<bool, StoreError> {
+    if dest.exists() {
+        let _ = std::fs::remove_file(partial);
+        if catalog::checksum(dest).map_err(StoreError::Catalog)? == checksum {
+            return Ok(false);
+        }
+        if let Some(master) = master {
+            if crypto::is_encrypted(dest).map_err(StoreError::Crypto)? {
+                let mut hasher = Sha256::new();
+                let existing = BufReader::new(File::open(dest)?);
+                crypto::decrypt_stream(master, existing, &mut hasher, false)
+                    .map_err(StoreError::Crypto)?;
+                if hex::encode(hasher.finalize()) == checksum {
+                    return Ok(false);
+                }
+            }
+        }
+        return Err(StoreError::Conflict);
+    }
+    std::fs::rename(partial, dest)?;
+    Ok(true)
+}
@@ -0,0 +1,206 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+
+use crate::catalog::{Chunk, ChunkState, SharedCatalog, Tier};
+use crate::crypto::PlainFile;
+use crate::model::Config;
+use crate::record;
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+/// Bucket of an S3 compatible object storage where chunks are
+/// archived
+pub(crate) struct Archive {
+    bucket: Bucket,
+}
+
+impl Archive {
+    /// The archive from the configuration, if there's one
+    pub(crate) fn open(config: &Config) -> Result<Option<Self>, Error> {
+        let archive = match &config.archive {
+            Some(archive) => archive,
+            None => return Ok(None),
+        };
+        let region = Region::Custom {
+            region: archive.region.clone(),
+            endpoint: archive.endpoint.clone(),
+        };
+        let credentials = Credentials::new(
+            Some(&archive.access_key),
+            Some(&archive.secret_key),
+            None,
+            None,
+            None,
+        )?;
+        let mut bucket = Bucket::new(&archive.bucket, region, credentials)?;
+        if archive.path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Some(Self { bucket }))
+    }
+
+    /// Upload a chunk exactly as it is on disk, encrypted or not
+    pub(crate) async fn upload(&self, chunk: &Chunk, path: &Path) -> Result<(), Error> {
+        let path = path.to_path_buf();
+        let data = actix_rt::task::spawn_blocking(move || std::fs::read(path)).await??;
+        let response = self.bucket.put_object(object_key(chunk), &data).await?;
+        check(response.status_code(), "upload", chunk)
+    }
+
+    pub(crate) async fn download(&self, chunk: &Chunk, dest: &Path) -> Result<(), Error> {
+        let response = self.bucket.get_object(object_key(chunk)).await?;
+        check(response.status_code(), "download", chunk)?;
+        let dest = dest.to_path_buf();
+        actix_rt::task::spawn_blocking(move || std::fs::write(dest, response.bytes())).await??;
+        Ok(())
+    }
+
+    pub(crate) async fn remove(&self, chunk: &Chunk) -> Result<(), Error> {
+        let response = self.bucket.delete_object(object_key(chunk)).await?;
+        check(response.status_code(), "remove", chunk)
+    }
+}
+
+/// Chunks are kept in the bucket under `JID/NAME'
+fn object_key(chunk: &Chunk) -> String {
+    format!("{}/{}", chunk.device, chunk.name)
+}
+
+fn check(status: u16, action: &str, chunk: &Chunk) -> Result<(), Error> {
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "Can't {} {}/{}: bucket answered {}",
+            action,
+            chunk.device,
+            chunk.name,
+            status,
+        ))
+    }
+}
+
+/// Run a future to completion on a thread that isn't running any
+/// event loop, like the ones storage spawns for its background work
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    actix_rt::System::new().block_on(future)
+}
+
+/// A chunk that can be read from disk, wherever it lives.  Chunks
+/// only found in the archive are downloaded to a temporary file
+/// that's removed once this is dropped.
+pub(crate) struct Fetched {
+    path: PathBuf,
+    downloaded: bool,
+}
+
+impl Fetched {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the chunk as plain media, decrypting it if needed
+    pub(crate) fn into_plain(mut self, config: &Config) -> Result<PlainFile, Error> {
+        if self.downloaded {
+            self.downloaded = false;
+            PlainFile::open_temporary(config, self.path.clone())
+        } else {
+            PlainFile::open(config, &self.path)
+        }
+    }
+}
+
+impl Drop for Fetched {
+    fn drop(&mut self) {
+        if self.downloaded {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Get hold of a chunk, from the disk if it's still there or from
+/// the archive otherwise
+pub(crate) async fn fetch(config: &Config, chunk: &Chunk) -> Result<Fetched, Error> {
+    let path = config.device_path(&chunk.device).join(&chunk.name);
+    if chunk.tier != Tier::Archived && path.exists() {
+        return Ok(Fetched {
+            path,
+            downloaded: false,
+        });
+    }
+    let archive = Archive::open(config)?
+        .ok_or_else(|| format_err!("Chunk {}/{} isn't on disk", chunk.device, chunk.name))?;
+    let dir = config.tmp_path().join("fetch");
+    std::fs::create_dir_all(&dir)?;
+    let dest = dir.join(format!(
+        "{}-{:08x}-{}",
+        record::now_ms(),
+        rand::random::<u32>(),
+        chunk.name,
+    ));
+    let fetched = Fetched {
+        path: dest,
+        downloaded: true,
+    };
+    archive.download(chunk, fetched.path()).await?;
+    Ok(fetched)
+}
+
+/// Upload finished chunks to the archive, in their own thread, once
+/// they're `archive.after_hours' old.  They stay on disk until the
+/// retention policies need the space.
+pub(crate) fn spawn(config: Config, catalog: SharedCatalog) -> Option<thread::JoinHandle<()>> {
+    let interval = config.archive.as_ref()?.interval;
+    Some(thread::spawn(move || {
+        block_on(async move {
+            loop {
+                if let Err(err) = archive_chunks(&config, &catalog).await {
+                    warn!("Archiving stopped: {}", err);
+                }
+                actix_rt::time::sleep(Duration::from_secs(interval)).await;
+            }
+        })
+    }))
+}
+
+async fn archive_chunks(config: &Config, catalog: &SharedCatalog) -> Result<(), Error> {
+    let archive = match Archive::open(config)? {
+        Some(archive) => archive,
+        None => return Ok(()),
+    };
+    let after = config.archive.as_ref().map_or(0, |a| a.after_hours) * HOUR_MS;
+    let now = record::now_ms();
+    let mut pending = catalog
+        .lock()
+        .unwrap()
+        .chunks()
+        .filter(|chunk| chunk.tier == Tier::Local)
+        .filter(|chunk| match chunk.state {
+            ChunkState::Complete | ChunkState::Repaired => true,
+            _ => false,
+        })
+        .filter(|chunk| chunk.end.map_or(false, |end| end + after <= now))
+        .cloned()
+        .collect::<Vec<Chunk>>();
+    pending.sort_by_key(|chunk| chunk.start);
+
+    for chunk in pending {
+        let path = config.device_path(&chunk.device).join(&chunk.name);
+        archive.upload(&chunk, &path).await?;
+
+        let mut catalog = catalog.lock().unwrap();
+        if let Some(current) = catalog.get(&chunk.device, chunk.start) {
+            let mut current = current.clone();
+            current.tier = Tier::Both;
+            catalog.put(current)?;
+        }
+        info!("Archived chunk {}/{}", chunk.device, chunk.name);
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use failure::Error;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_derive::Deserialize;
+
+use crate::model::Config;
+
+/// What storage needs out of the tokens the server issues.  Whether
+/// they expired is checked along the way.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    /// `device', `viewer' or `storage', as given in the server's
+    /// `credentials'
+    role: String,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Token of {} is for a {}, not a {}", _0, _1, _2)]
+pub(crate) struct WrongRole(String, String, &'static str);
+
+/// JID a token from the server was issued to, as long as it's still
+/// valid and it was issued for `role'
+pub(crate) fn jid_from_token(
+    secret: &str,
+    token: &str,
+    role: &'static str,
+) -> Result<String, Error> {
+    let key = DecodingKey::from_secret(secret.as_bytes());
+    let data = decode::<Claims>(token, &key, &Validation::new(Algorithm::HS512))?;
+    if data.claims.role != role {
+        return Err(WrongRole(data.claims.sub, data.claims.role, role).into());
+    }
+    Ok(data.claims.sub)
+}
+
+/// Whether a device proved who it is with a token from the server.
+/// Any device is let through when `[auth]' isn't set.
+pub(crate) fn is_authorized(config: &Config, device: &str, token: Option<&str>) -> bool {
+    let auth = match &config.auth {
+        Some(auth) => auth,
+        None => return true,
+    };
+    match token.map(|token| jid_from_token(&auth.jwt_secret, token, "device")) {
+        Some(Ok(jid)) => jid == device,
+        Some(Err(err)) => {
+            warn!("Refusing token of {}: {}", device, err);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Whether someone asking for recordings carries a viewer token from
+/// the server.  The server lets every viewer watch every device, so
+/// any of them will do, but device tokens won't.  Everyone is let
+/// through when `[auth]' isn't set.
+pub(crate) fn is_viewer(config: &Config, token: Option<&str>) -> bool {
+    let auth = match &config.auth {
+        Some(auth) => auth,
+        None => return true,
+    };
+    match token.map(|token| jid_from_token(&auth.jwt_secret, token, "viewer")) {
+        Some(Ok(_)) => true,
+        Some(Err(err)) => {
+            warn!("Refusing token of viewer: {}", err);
+            false
+        }
+        None => false,
+    }
+}
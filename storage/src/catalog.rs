@@ -0,0 +1,710 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use gst::prelude::*;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::model::Config;
+use crate::{crypto, playback, record};
+
+/// Name of the index file, written under `storage.path'
+const CATALOG_FILE: &str = "catalog.jsonl";
+
+/// The catalog is shared between the RTSP server, which writes the
+/// chunks, and the HTTP API, which reads them
+pub(crate) type SharedCatalog = Arc<Mutex<Catalog>>;
+
+/// Where a chunk is in its life
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChunkState {
+    /// Still being written
+    Recording,
+    /// Finished by the muxer
+    Complete,
+    /// Left unfinished, then remuxed with what could be read out of it
+    Repaired,
+    /// Left unfinished and nothing could be read out of it.  It's
+    /// moved out of the device's directory.
+    Quarantined,
+    /// Deleted by the retention policies.  The record is kept so the
+    /// chain of the device still holds.
+    Deleted,
+}
+
+impl Default for ChunkState {
+    fn default() -> Self {
+        ChunkState::Complete
+    }
+}
+
+/// Where a chunk lives
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Tier {
+    /// Only on disk
+    Local,
+    /// Uploaded to the archive and still on disk
+    Both,
+    /// Only in the archive
+    Archived,
+}
+
+impl Default for Tier {
+    fn default() -> Self {
+        Tier::Local
+    }
+}
+
+/// Everything storage knows about a chunk
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Chunk {
+    /// JID of the device that recorded the chunk
+    pub(crate) device: String,
+    /// Name of the file within the device's directory
+    pub(crate) name: String,
+    /// Wall-clock time in which the chunk started, in milliseconds
+    /// since the UNIX epoch
+    pub(crate) start: u64,
+    /// Wall-clock time in which the chunk ended.  Chunks still being
+    /// written don't have one.
+    pub(crate) end: Option<u64>,
+    /// Duration of the media within the chunk, in milliseconds
+    pub(crate) duration: Option<u64>,
+    /// Size of the file, in bytes
+    pub(crate) size: u64,
+    pub(crate) codec: Option<String>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    /// Hex encoded SHA-256 of the file
+    pub(crate) checksum: Option<String>,
+    #[serde(default)]
+    pub(crate) state: ChunkState,
+    /// Whether the file is encrypted.  The checksum is the one of the
+    /// encrypted file.
+    #[serde(default)]
+    pub(crate) encrypted: bool,
+    /// Pinned chunks are never deleted by the retention policies
+    #[serde(default)]
+    pub(crate) pinned: bool,
+    /// Whether something worth keeping happened during the chunk
+    #[serde(default)]
+    pub(crate) event: bool,
+    /// Position of the chunk within the chain of its device, in the
+    /// order chunks were finished
+    #[serde(default)]
+    pub(crate) sequence: Option<u64>,
+    /// Link of the chunk finished right before this one
+    #[serde(default)]
+    pub(crate) previous: Option<String>,
+    /// Hex encoded HMAC-SHA256, keyed with `chain.secret', over the
+    /// previous link and every field of this chunk that doesn't
+    /// change once it's finished
+    #[serde(default)]
+    pub(crate) chain: Option<String>,
+    /// Bumped every time the record changes
+    #[serde(default)]
+    pub(crate) revision: u64,
+    /// Last revision of the record the replication peer has
+    #[serde(default)]
+    pub(crate) replicated: Option<u64>,
+    #[serde(default)]
+    pub(crate) tier: Tier,
+    /// Whether thumbnails were extracted out of the chunk
+    #[serde(default)]
+    pub(crate) thumbnails: bool,
+}
+
+impl Chunk {
+    /// Record for a chunk that was just opened
+    pub(crate) fn new(device: &str, name: &str, start: u64) -> Self {
+        Self {
+            device: device.to_string(),
+            name: name.to_string(),
+            start,
+            end: None,
+            duration: None,
+            size: 0,
+            codec: None,
+            width: None,
+            height: None,
+            checksum: None,
+            state: ChunkState::Recording,
+            encrypted: false,
+            pinned: false,
+            event: false,
+            sequence: None,
+            previous: None,
+            chain: None,
+            revision: 0,
+            replicated: None,
+            tier: Tier::Local,
+            thumbnails: false,
+        }
+    }
+
+    /// Finished chunks with a checksum get linked to the chain of
+    /// their device
+    fn is_sealable(&self) -> bool {
+        self.chain.is_none()
+            && self.checksum.is_some()
+            && match self.state {
+                ChunkState::Complete | ChunkState::Repaired | ChunkState::Quarantined => true,
+                ChunkState::Recording | ChunkState::Deleted => false,
+            }
+    }
+
+    /// Chunks still being written only count as overlapping once
+    /// they started, and quarantined chunks only at the time they
+    /// started
+    pub(crate) fn overlaps(&self, start: u64, end: u64) -> bool {
+        let chunk_end = match (self.end, self.state) {
+            (Some(end), _) => end,
+            (None, ChunkState::Recording) => u64::MAX,
+            (None, _) => self.start + 1,
+        };
+        self.start < end && chunk_end > start
+    }
+}
+
+/// Index with one record per chunk.  It's kept in memory and backed
+/// by a file in which every change to a chunk is appended as one JSON
+/// line.  The last line about a chunk wins.  The file is compacted
+/// each time storage starts.
+///
+/// Once a chunk is finished it's sealed: it's linked to the chunk of
+/// the same device finished before it by a hash over both, so the
+/// chunks of each device form a chain that can't be changed without
+/// it showing.
+pub(crate) struct Catalog {
+    file: File,
+    chunks: BTreeMap<(String, u64), Chunk>,
+    /// Sequence number and link of the last sealed chunk of each
+    /// device
+    tips: HashMap<String, (u64, String)>,
+    /// Key of the links, `chain.secret'.  Chunks aren't sealed
+    /// without it.
+    secret: Option<String>,
+}
+
+impl Catalog {
+    /// Load the catalog and reconcile it with what's actually on
+    /// disk.  Chunks that are gone are dropped, and chunks the index
+    /// doesn't know about (or knows wrong) are probed again.  Chunks
+    /// left unfinished, because storage died while writing them, are
+    /// repaired or quarantined.
+    pub(crate) fn open(config: &Config) -> Result<Self, Error> {
+        std::fs::create_dir_all(&config.storage.path)?;
+        let path = Path::new(&config.storage.path).join(CATALOG_FILE);
+        let mut chunks = read_index(&path)?;
+        let mut found = BTreeMap::new();
+
+        for jid in config.devices() {
+            for (start, file) in list_chunks(&config.device_path(jid))? {
+                let key = (jid.clone(), start);
+                let size = std::fs::metadata(&file)?.len();
+                let chunk = match chunks.remove(&key) {
+                    Some(chunk)
+                        if chunk.end.is_some()
+                            && chunk.size == size
+                            && chunk.state != ChunkState::Deleted =>
+                    {
+                        chunk
+                    }
+                    // Sealed records stay as they are, so `verify' can
+                    // tell the file isn't the one that was sealed
+                    Some(mut chunk)
+                        if chunk.chain.is_some() && chunk.state != ChunkState::Quarantined =>
+                    {
+                        if chunk.state == ChunkState::Deleted {
+                            warn!("Deleted chunk {:?} is back", file);
+                            chunk.state = ChunkState::Complete;
+                        } else {
+                            warn!("Chunk {:?} changed since it was sealed", file);
+                        }
+                        chunk
+                    }
+                    known => {
+                        let unfinished = known
+                            .as_ref()
+                            .map(|c| c.state == ChunkState::Recording)
+                            .unwrap_or(false);
+                        let mut chunk = if unfinished {
+                            recover_chunk(config, jid, &file)?
+                        } else {
+                            info!("Adding {:?} to the catalog", file);
+                            match probe_chunk(config, jid, &file)? {
+                                chunk if chunk.duration.unwrap_or(0) > 0 => chunk,
+                                _ => recover_chunk(config, jid, &file)?,
+                            }
+                        };
+                        if let Some(known) = known {
+                            chunk.pinned = known.pinned;
+                            chunk.event = known.event;
+                        }
+                        chunk
+                    }
+                };
+                found.insert(key, chunk);
+            }
+        }
+
+        for (key, chunk) in chunks {
+            let quarantined = config.quarantine_path(&chunk.device).join(&chunk.name);
+            if chunk.state == ChunkState::Deleted || chunk.tier != Tier::Local {
+                found.insert(key, chunk);
+            } else if chunk.state == ChunkState::Quarantined && quarantined.exists() {
+                found.insert(key, chunk);
+            } else if chunk.chain.is_some() {
+                // Keeping it lets `verify' report the missing file
+                warn!("Sealed chunk {}/{} is gone", chunk.device, chunk.name);
+                found.insert(key, chunk);
+            } else {
+                info!("Chunk {}/{} is gone", chunk.device, chunk.name);
+            }
+        }
+
+        // Chunks found without a link, like the ones recorded before
+        // chains existed or while they were off, are sealed in the
+        // order they started
+        let secret = config.chain.as_ref().map(|chain| chain.secret.clone());
+        if secret.is_none() {
+            warn!("Chunks aren't chained, set `chain.secret' to seal them");
+        }
+        let mut tips = HashMap::new();
+        for chunk in found.values() {
+            if let (Some(sequence), Some(link)) = (chunk.sequence, &chunk.chain) {
+                let tip = tips
+                    .entry(chunk.device.clone())
+                    .or_insert((sequence, link.clone()));
+                if sequence > tip.0 {
+                    *tip = (sequence, link.clone());
+                }
+            }
+        }
+        if let Some(secret) = &secret {
+            for chunk in found.values_mut() {
+                if chunk.is_sealable() {
+                    seal(secret, &mut tips, chunk)?;
+                }
+            }
+        }
+
+        // Rewrite the index with only what was found
+        let partial = path.with_extension("part");
+        let mut file = File::create(&partial)?;
+        for chunk in found.values() {
+            writeln!(file, "{}", serde_json::to_string(chunk)?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&partial, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        info!("Catalog loaded with {} chunks", found.len());
+        Ok(Self {
+            file,
+            chunks: found,
+            tips,
+            secret,
+        })
+    }
+
+    /// Add or update a chunk.  Chunks that were just finished are
+    /// sealed on the way in, when chains are on, and chunks
+    /// replicated from a peer come already sealed.
+    pub(crate) fn put(&mut self, mut chunk: Chunk) -> Result<(), Error> {
+        let key = (chunk.device.clone(), chunk.start);
+        chunk.revision = self.chunks.get(&key).map(|c| c.revision + 1).unwrap_or(0);
+        if chunk.is_sealable() {
+            if let Some(secret) = &self.secret {
+                seal(secret, &mut self.tips, &mut chunk)?;
+            }
+        } else if let (Some(sequence), Some(link)) = (chunk.sequence, &chunk.chain) {
+            if self
+                .tips
+                .get(&chunk.device)
+                .map_or(true, |(tip, _)| sequence > *tip)
+            {
+                self.tips
+                    .insert(chunk.device.clone(), (sequence, link.clone()));
+            }
+        }
+        writeln!(self.file, "{}", serde_json::to_string(&chunk)?)?;
+        self.chunks.insert(key, chunk);
+        Ok(())
+    }
+
+    /// Take note that the peer has a revision of a chunk's record.
+    /// Nothing happens if the record changed in the meantime, so the
+    /// new revision is replicated as well.
+    pub(crate) fn mark_replicated(
+        &mut self,
+        device: &str,
+        start: u64,
+        revision: u64,
+    ) -> Result<(), Error> {
+        if let Some(chunk) = self.chunks.get_mut(&(device.to_string(), start)) {
+            if chunk.revision == revision {
+                chunk.replicated = Some(revision);
+                writeln!(self.file, "{}", serde_json::to_string(chunk)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, device: &str, start: u64) -> Option<&Chunk> {
+        self.chunks
+            .get(&(device.to_string(), start))
+            .filter(|chunk| chunk.state != ChunkState::Deleted)
+    }
+
+    /// All the chunks of all the devices, except deleted ones
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks
+            .values()
+            .filter(|chunk| chunk.state != ChunkState::Deleted)
+    }
+
+    /// Every record of every device, deleted ones included
+    pub(crate) fn records(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.values()
+    }
+
+    /// Whether a sealed record from a replication peer belongs in the
+    /// chain of its device here.  Its link has to be the one this
+    /// storage's secret gives, and a record not seen before has to
+    /// follow the last one sealed for its device.
+    pub(crate) fn links(&self, chunk: &Chunk) -> Result<bool, Error> {
+        let (secret, sequence, link) = match (&self.secret, chunk.sequence, &chunk.chain) {
+            (Some(secret), Some(sequence), Some(link)) => (secret, sequence, link),
+            _ => return Ok(false),
+        };
+        if &chain_link(secret, chunk)? != link {
+            return Ok(false);
+        }
+        if let Some(known) = self.chunks.get(&(chunk.device.clone(), chunk.start)) {
+            return Ok(known.chain.as_ref() == Some(link));
+        }
+        Ok(match self.tips.get(&chunk.device) {
+            Some((tip, tip_link)) => {
+                sequence == tip + 1 && chunk.previous.as_ref() == Some(tip_link)
+            }
+            None => sequence == 0 && chunk.previous.is_none(),
+        })
+    }
+
+    /// Chunks of a device overlapping the interval between `start'
+    /// and `end', oldest first
+    pub(crate) fn range(&self, device: &str, start: u64, end: u64) -> Vec<&Chunk> {
+        let from = (device.to_string(), 0);
+        let to = (device.to_string(), end);
+        self.chunks
+            .range(from..to)
+            .map(|(_, chunk)| chunk)
+            .filter(|chunk| chunk.state != ChunkState::Deleted)
+            .filter(|chunk| chunk.overlaps(start, end))
+            .collect()
+    }
+
+    /// Every record of a device, deleted ones included, oldest first
+    pub(crate) fn timeline<'a>(&'a self, device: &str) -> impl Iterator<Item = &'a Chunk> {
+        let from = (device.to_string(), 0);
+        let to = (device.to_string(), u64::MAX);
+        self.chunks.range(from..=to).map(|(_, chunk)| chunk)
+    }
+}
+
+/// Link a chunk to the last sealed chunk of its device
+fn seal(
+    secret: &str,
+    tips: &mut HashMap<String, (u64, String)>,
+    chunk: &mut Chunk,
+) -> Result<(), Error> {
+    let (sequence, previous) = match tips.get(&chunk.device) {
+        Some((sequence, link)) => (sequence + 1, Some(link.clone())),
+        None => (0, None),
+    };
+    chunk.sequence = Some(sequence);
+    chunk.previous = previous;
+    let link = chain_link(secret, chunk)?;
+    chunk.chain = Some(link.clone());
+    tips.insert(chunk.device.clone(), (sequence, link));
+    Ok(())
+}
+
+/// Hex encoded link of a chunk to the previous one.  It covers every
+/// field set once the chunk is finished; the state, tier, pins,
+/// events and thumbnails change afterwards and are left out.  It's
+/// an HMAC-SHA256 keyed with the secret, so the chain can't be
+/// rebuilt without it.
+pub(crate) fn chain_link(secret: &str, chunk: &Chunk) -> Result<String, Error> {
+    let record = serde_json::to_vec(&(
+        &chunk.previous,
+        chunk.sequence,
+        &chunk.device,
+        &chunk.name,
+        chunk.start,
+        chunk.end,
+        chunk.duration,
+        chunk.size,
+        &chunk.codec,
+        chunk.width,
+        chunk.height,
+        &chunk.checksum,
+        chunk.encrypted,
+    ))?;
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(&record)?;
+    Ok(hex::encode(signer.sign_to_vec()?))
+}
+
+/// Every record of a device, deleted ones included, read straight
+/// from the index without touching it.  It's safe to call while
+/// storage is running.
+pub(crate) fn read_history(config: &Config, device: &str) -> Result<Vec<Chunk>, Error> {
+    let path = Path::new(&config.storage.path).join(CATALOG_FILE);
+    let mut chunks = read_index(&path)?
+        .into_iter()
+        .map(|(_, chunk)| chunk)
+        .filter(|chunk| chunk.device == device)
+        .collect::<Vec<Chunk>>();
+    chunks.sort_by_key(|chunk| (chunk.sequence.unwrap_or(u64::MAX), chunk.start));
+    Ok(chunks)
+}
+
+/// Read the chunks from the index file.  Lines that can't be parsed,
+/// like a last line cut short by a crash, are skipped.
+fn read_index(path: &Path) -> Result<BTreeMap<(String, u64), Chunk>, Error> {
+    let mut chunks = BTreeMap::new();
+    if !path.exists() {
+        return Ok(chunks);
+    }
+    for line in BufReader::new(File::open(path)?).lines() {
+        match serde_json::from_str::<Chunk>(&line?) {
+            Ok(chunk) => {
+                chunks.insert((chunk.device.clone(), chunk.start), chunk);
+            }
+            Err(err) => warn!("Skipping catalog entry: {}", err),
+        }
+    }
+    Ok(chunks)
+}
+
+/// Chunk files within a device's directory and the time they started
+fn list_chunks(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Error> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut chunks = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if let Some(start) = record::parse_chunk_name(name) {
+            chunks.push((start, path));
+        }
+    }
+    chunks.sort();
+    Ok(chunks)
+}
+
+/// Build the record of a finished chunk by inspecting its file, which
+/// is decrypted first if needed
+pub(crate) fn probe_chunk(config: &Config, device: &str, path: &Path) -> Result<Chunk, Error> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    let start = record::parse_chunk_name(&name).unwrap_or(0);
+    let mut chunk = Chunk::new(device, &name, start);
+    chunk.state = ChunkState::Complete;
+    chunk.size = std::fs::metadata(path)?.len();
+    chunk.checksum = Some(checksum(path)?);
+
+    let plain = crypto::PlainFile::open(config, path)?;
+    chunk.encrypted = plain.is_temporary();
+    discover(&mut chunk, path, plain.path())?;
+    Ok(chunk)
+}
+
+/// Fill the duration, end, codec and resolution of the chunk at
+/// `path' in out of its plain media at `plain'
+fn discover(chunk: &mut Chunk, path: &Path, plain: &Path) -> Result<(), Error> {
+    let discoverer = gst_pbutils::Discoverer::new(gst::ClockTime::from_seconds(10))?;
+    let uri = glib::filename_to_uri(plain, None)?;
+    match discoverer.discover_uri(&uri) {
+        Ok(info) => {
+            chunk.duration = info.get_duration().mseconds();
+            if let Some(stream) = info.get_video_streams().into_iter().next() {
+                if let Some(caps) = stream.get_caps() {
+                    chunk.codec = caps.get_structure(0).map(|s| codec_name(s.get_name()));
+                }
+                if let Ok(video) = stream.downcast::<gst_pbutils::DiscovererVideoInfo>() {
+                    chunk.width = Some(video.get_width());
+                    chunk.height = Some(video.get_height());
+                }
+            }
+        }
+        Err(err) => warn!("Can't probe {:?}: {}", path, err),
+    }
+    chunk.end = Some(chunk.start + chunk.duration.unwrap_or(0));
+    Ok(())
+}
+
+/// Deal with a chunk that was never finished.  Chunks are written as
+/// fragmented MP4, encrypted a record at a time if at all, so
+/// whatever made it to complete fragments can be remuxed into a
+/// proper file that replaces the chunk.  The remuxed media stays in
+/// memory when chunks are encrypted.  When nothing can be read out of
+/// it, the chunk is moved to the quarantine directory and kept in the
+/// catalog as quarantined.
+fn recover_chunk(config: &Config, device: &str, path: &Path) -> Result<Chunk, Error> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    let mut chunk = Chunk::new(device, &name, record::parse_chunk_name(&name).unwrap_or(0));
+
+    let repaired = crypto::PlainFile::recover(config, path).and_then(|plain| {
+        let repaired = crypto::PlainFile::scratch(config, &name)?;
+        playback::repair(plain.path(), repaired.path())?;
+        Ok(repaired)
+    });
+    match repaired {
+        Ok(repaired) => {
+            discover(&mut chunk, path, repaired.path())?;
+            if chunk.duration.unwrap_or(0) > 0 {
+                let partial = path.with_extension("part");
+                match crypto::master_key(config)? {
+                    Some(master) => {
+                        crypto::encrypt_file(&master, repaired.path(), &partial)?;
+                        chunk.encrypted = true;
+                    }
+                    None => {
+                        std::fs::copy(repaired.path(), &partial)?;
+                    }
+                }
+                std::fs::rename(&partial, path)?;
+                chunk.size = std::fs::metadata(path)?.len();
+                chunk.checksum = Some(checksum(path)?);
+                chunk.state = ChunkState::Repaired;
+                warn!(
+                    "Repaired unfinished chunk {:?}, {} ms of media recovered",
+                    path,
+                    chunk.duration.unwrap_or(0),
+                );
+                return Ok(chunk);
+            }
+            chunk.duration = None;
+            chunk.end = None;
+        }
+        Err(err) => warn!("Can't repair unfinished chunk {:?}: {}", path, err),
+    }
+
+    chunk.size = std::fs::metadata(path)?.len();
+    chunk.checksum = Some(checksum(path)?);
+    chunk.state = ChunkState::Quarantined;
+
+    let dir = config.quarantine_path(device);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::rename(path, dir.join(&name))?;
+    warn!("Quarantined unfinished chunk {:?} into {:?}", path, dir);
+    Ok(chunk)
+}
+
+/// Hex encoded SHA-256 of a file
+pub(crate) fn checksum(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Short codec name out of a caps name, like `h264' for
+/// `video/x-h264'
+pub(crate) fn codec_name(caps_name: &str) -> String {
+    let name = caps_name.rsplit('/').next().unwrap_or(caps_name);
+    name.strip_prefix("x-").unwrap_or(name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, DEVICE};
+
+    /// A chunk cut off in the middle of a fragment, the way a crash
+    /// leaves it
+    fn cut_off_chunk(config: &Config, name: &str) -> PathBuf {
+        let path = config.device_path(DEVICE).join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        testing::record_fragmented(&path);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() * 3 / 4]).unwrap();
+        path
+    }
+
+    #[test]
+    fn cut_off_chunks_are_repaired() {
+        let dir = testing::scratch_dir("repair");
+        let config = testing::config(&dir, "");
+        let path = cut_off_chunk(&config, "2000.mp4");
+
+        let chunk = recover_chunk(&config, DEVICE, &path).unwrap();
+        assert_eq!(chunk.state, ChunkState::Repaired);
+        let duration = chunk.duration.unwrap();
+        assert!(duration > 0 && duration < 5000, "{} ms", duration);
+        assert_eq!(chunk.end, Some(2000 + duration));
+        assert_eq!(chunk.codec.as_deref(), Some("h264"));
+        assert_eq!(chunk.checksum, Some(checksum(&path).unwrap()));
+        assert!(!chunk.encrypted);
+    }
+
+    #[test]
+    fn cut_off_encrypted_chunks_are_repaired_encrypted() {
+        let dir = testing::scratch_dir("repair-encrypted");
+        let extra = format!("[encryption]\nkey = '{}'", testing::KEY);
+        let config = testing::config(&dir, &extra);
+        let master = crypto::master_key(&config).unwrap().unwrap();
+        let plain = cut_off_chunk(&config, "3000.part");
+        let path = config.device_path(DEVICE).join("3000.mp4");
+        // Sealed a record at a time and never finished
+        let mut writer = crypto::ChunkWriter::new(&master, File::create(&path).unwrap()).unwrap();
+        std::io::copy(&mut File::open(&plain).unwrap(), &mut writer).unwrap();
+        drop(writer);
+        std::fs::remove_file(&plain).unwrap();
+
+        let chunk = recover_chunk(&config, DEVICE, &path).unwrap();
+        assert_eq!(chunk.state, ChunkState::Repaired);
+        assert!(chunk.duration.unwrap() > 0);
+        assert!(chunk.encrypted);
+        assert!(crypto::is_encrypted(&path).unwrap());
+        assert_eq!(chunk.checksum, Some(checksum(&path).unwrap()));
+    }
+
+    #[test]
+    fn unreadable_chunks_are_quarantined() {
+        gst::init().unwrap();
+        let dir = testing::scratch_dir("quarantine");
+        let config = testing::config(&dir, "");
+        let path = config.device_path(DEVICE).join("1000.mp4");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"not an mp4 at all").unwrap();
+
+        let chunk = recover_chunk(&config, DEVICE, &path).unwrap();
+        assert_eq!(chunk.state, ChunkState::Quarantined);
+        assert_eq!(chunk.start, 1000);
+        assert!(!path.exists());
+        let moved = config.quarantine_path(DEVICE).join("1000.mp4");
+        assert_eq!(std::fs::read(&moved).unwrap(), b"not an mp4 at all");
+        assert_eq!(chunk.checksum, Some(checksum(&moved).unwrap()));
+    }
+}
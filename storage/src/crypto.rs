@@ -0,0 +1,552 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use failure::Error;
+use rand::RngCore;
+
+use crate::model::Config;
+use crate::record;
+
+/// First bytes of every file encrypted as a whole, also used as
+/// associated data so the header can't be swapped around
+const MAGIC: &[u8; 8] = b"UCAMENC1";
+/// First bytes of every chunk encrypted as it's written, one record
+/// at a time
+const STREAM_MAGIC: &[u8; 8] = b"UCAMENC2";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const WRAPPED_KEY_SIZE: usize = KEY_SIZE + TAG_SIZE;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_SIZE + WRAPPED_KEY_SIZE + NONCE_SIZE;
+/// Most media sealed in a single record of a chunk
+pub(crate) const RECORD_SIZE: usize = 256 * 1024;
+/// Length, flags and nonce of each record
+const RECORD_HEADER_SIZE: usize = 4 + 1 + NONCE_SIZE;
+/// Flag of the record that closes a chunk
+const LAST_RECORD: u8 = 1;
+
+#[derive(Debug, Fail)]
+#[fail(display = "The encryption key must be 64 hex digits")]
+pub(crate) struct InvalidKey;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Chunk is encrypted and no encryption key is configured")]
+pub(crate) struct NoKey;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Can't decrypt, wrong key or damaged file")]
+pub(crate) struct DecryptError;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Can't encrypt")]
+pub(crate) struct EncryptError;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Encrypted chunk was cut short")]
+pub(crate) struct Truncated;
+
+/// The key each chunk's own key is wrapped with
+#[derive(Clone)]
+pub(crate) struct MasterKey([u8; KEY_SIZE]);
+
+/// The master key from the configuration, if chunks are to be
+/// encrypted
+pub(crate) fn master_key(config: &Config) -> Result<Option<MasterKey>, Error> {
+    let encryption = match &config.encryption {
+        Some(encryption) => encryption,
+        None => return Ok(None),
+    };
+    let bytes = hex::decode(encryption.key.trim()).map_err(|_| InvalidKey)?;
+    if bytes.len() != KEY_SIZE {
+        return Err(InvalidKey.into());
+    }
+    let mut key = [0; KEY_SIZE];
+    key.copy_from_slice(&bytes);
+    Ok(Some(MasterKey(key)))
+}
+
+/// Encrypt a file, like a thumbnail, with a key of its own, which is
+/// stored in the header wrapped by the master key.  The layout is the
+/// magic bytes, the nonce and the wrapped file key, then the nonce
+/// and the encrypted data, both with AES-256-GCM.
+pub(crate) fn encrypt(master: &MasterKey, plain: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut chunk_key = [0; KEY_SIZE];
+    let mut wrap_nonce = [0; NONCE_SIZE];
+    let mut data_nonce = [0; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut chunk_key);
+    rand::thread_rng().fill_bytes(&mut wrap_nonce);
+    rand::thread_rng().fill_bytes(&mut data_nonce);
+
+    let wrapped = cipher(&master.0)
+        .encrypt(Nonce::from_slice(&wrap_nonce), seal(&chunk_key))
+        .map_err(|_| EncryptError)?;
+    let data = cipher(&chunk_key)
+        .encrypt(Nonce::from_slice(&data_nonce), seal(plain))
+        .map_err(|_| EncryptError)?;
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + data.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&wrap_nonce);
+    out.extend_from_slice(&wrapped);
+    out.extend_from_slice(&data_nonce);
+    out.extend_from_slice(&data);
+    Ok(out)
+}
+
+/// Undo what `encrypt' did, failing if anything was tampered with
+pub(crate) fn decrypt(master: &MasterKey, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if !is_encrypted_data(data) || data.len() < HEADER_SIZE + TAG_SIZE {
+        return Err(DecryptError.into());
+    }
+    let (wrap_nonce, rest) = data[MAGIC.len()..].split_at(NONCE_SIZE);
+    let (wrapped, rest) = rest.split_at(WRAPPED_KEY_SIZE);
+    let (data_nonce, rest) = rest.split_at(NONCE_SIZE);
+
+    let chunk_key = cipher(&master.0)
+        .decrypt(Nonce::from_slice(wrap_nonce), seal(wrapped))
+        .map_err(|_| DecryptError)?;
+    let plain = cipher(&chunk_key)
+        .decrypt(Nonce::from_slice(data_nonce), seal(rest))
+        .map_err(|_| DecryptError)?;
+    Ok(plain)
+}
+
+pub(crate) fn is_encrypted_data(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(STREAM_MAGIC)
+}
+
+/// Whether a file starts like an encrypted file or chunk
+pub(crate) fn is_encrypted(path: &Path) -> Result<bool, Error> {
+    let mut magic = [0; MAGIC.len()];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(is_encrypted_data(&magic)),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Encrypts a chunk as it's written, so its media never reaches the
+/// disk in the clear.  The layout starts with the stream magic bytes,
+/// the nonce and the wrapped chunk key, like `encrypt', followed by a
+/// record for every write: the length of the encrypted media, the
+/// flags and the nonce, then the encrypted media.  Where each record
+/// goes and whether it's the last one are sealed along with it, so
+/// records can't be dropped, reordered or cut off unnoticed.
+pub(crate) struct ChunkWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    index: u64,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub(crate) fn new(master: &MasterKey, mut inner: W) -> Result<Self, Error> {
+        let mut chunk_key = [0; KEY_SIZE];
+        let mut wrap_nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut chunk_key);
+        rand::thread_rng().fill_bytes(&mut wrap_nonce);
+        let wrapped = cipher(&master.0)
+            .encrypt(
+                Nonce::from_slice(&wrap_nonce),
+                Payload {
+                    msg: &chunk_key,
+                    aad: STREAM_MAGIC,
+                },
+            )
+            .map_err(|_| EncryptError)?;
+
+        inner.write_all(STREAM_MAGIC)?;
+        inner.write_all(&wrap_nonce)?;
+        inner.write_all(&wrapped)?;
+        Ok(Self {
+            inner,
+            cipher: cipher(&chunk_key),
+            index: 0,
+        })
+    }
+
+    /// Close the chunk with an empty last record and hand back what
+    /// it was written to
+    pub(crate) fn finish(mut self) -> Result<W, Error> {
+        self.seal(&[], LAST_RECORD)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn seal(&mut self, plain: &[u8], flags: u8) -> io::Result<()> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let data = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plain,
+                    aad: &record_aad(self.index, flags),
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, EncryptError.to_string()))?;
+        self.inner.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&[flags])?;
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&data)?;
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    /// Every write is sealed right away in a record of its own
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let plain = &buf[..buf.len().min(RECORD_SIZE)];
+        self.seal(plain, 0)?;
+        Ok(plain.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Associated data of a record, its position in the chunk and its
+/// flags
+fn record_aad(index: u64, flags: u8) -> Vec<u8> {
+    let mut aad = STREAM_MAGIC.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(flags);
+    aad
+}
+
+/// Decrypt what was encrypted by either `encrypt' or `ChunkWriter'
+/// from `src' into `dest'.  With `partial', a chunk that was cut
+/// short, like one storage never got to finish, is decrypted up to
+/// its last whole record instead of failing.
+pub(crate) fn decrypt_stream<R: Read, W: Write>(
+    master: &MasterKey,
+    mut src: R,
+    dest: &mut W,
+    partial: bool,
+) -> Result<(), Error> {
+    let mut magic = [0; MAGIC.len()];
+    if !read_whole(&mut src, &mut magic)? {
+        return Err(DecryptError.into());
+    }
+    if &magic == MAGIC {
+        let mut data = magic.to_vec();
+        src.read_to_end(&mut data)?;
+        dest.write_all(&decrypt(master, &data)?)?;
+        return Ok(());
+    }
+    if &magic != STREAM_MAGIC {
+        return Err(DecryptError.into());
+    }
+
+    let mut wrap_nonce = [0; NONCE_SIZE];
+    let mut wrapped = [0; WRAPPED_KEY_SIZE];
+    if !read_whole(&mut src, &mut wrap_nonce)? || !read_whole(&mut src, &mut wrapped)? {
+        return Err(DecryptError.into());
+    }
+    let chunk_key = cipher(&master.0)
+        .decrypt(
+            Nonce::from_slice(&wrap_nonce),
+            Payload {
+                msg: &wrapped,
+                aad: STREAM_MAGIC,
+            },
+        )
+        .map_err(|_| DecryptError)?;
+    let chunk_cipher = cipher(&chunk_key);
+
+    let mut index = 0;
+    loop {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        if !read_whole(&mut src, &mut header)? {
+            break;
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[..4]);
+        let len = u32::from_be_bytes(len) as usize;
+        let flags = header[4];
+        if len < TAG_SIZE || len > RECORD_SIZE + TAG_SIZE {
+            return Err(DecryptError.into());
+        }
+        let mut data = vec![0; len];
+        if !read_whole(&mut src, &mut data)? {
+            break;
+        }
+        let plain = chunk_cipher
+            .decrypt(
+                Nonce::from_slice(&header[5..]),
+                Payload {
+                    msg: &data,
+                    aad: &record_aad(index, flags),
+                },
+            )
+            .map_err(|_| DecryptError)?;
+        dest.write_all(&plain)?;
+        if flags & LAST_RECORD != 0 {
+            return Ok(());
+        }
+        index += 1;
+    }
+
+    if partial {
+        Ok(())
+    } else {
+        Err(Truncated.into())
+    }
+}
+
+/// Fill `buf' up, or tell the end of the file came first
+fn read_whole<R: Read>(src: &mut R, buf: &mut [u8]) -> Result<bool, Error> {
+    match src.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write an encrypted copy of a plain chunk to `dest'
+pub(crate) fn encrypt_file(master: &MasterKey, src: &Path, dest: &Path) -> Result<(), Error> {
+    let mut writer = ChunkWriter::new(master, File::create(dest)?)?;
+    io::copy(&mut File::open(src)?, &mut writer)?;
+    writer.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Write the decrypted version of an encrypted chunk to `dest'
+pub(crate) fn decrypt_file(master: &MasterKey, src: &Path, dest: &Path) -> Result<(), Error> {
+    let mut file = File::create(dest)?;
+    decrypt_stream(master, BufReader::new(File::open(src)?), &mut file, false)
+}
+
+/// A file that only lives in memory.  Anything that wants a path,
+/// like GStreamer elements, can still open it under `/proc/self/fd'.
+fn memory_file(name: &str) -> Result<(File, PathBuf), Error> {
+    let name = CString::new(name)?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    Ok((file, PathBuf::from(format!("/proc/self/fd/{}", fd))))
+}
+
+/// A chunk that can be read as plain media.  Chunks that aren't
+/// encrypted are read right where they are, the others are decrypted
+/// into memory that's released once this is dropped, so their media
+/// never reaches the disk in the clear.
+pub(crate) struct PlainFile {
+    path: PathBuf,
+    /// What the chunk was decrypted into, if it was
+    memory: Option<File>,
+    /// Whether the file is a copy on disk that's removed once this is
+    /// dropped
+    temporary: bool,
+}
+
+impl PlainFile {
+    pub(crate) fn open(config: &Config, path: &Path) -> Result<Self, Error> {
+        Self::decrypt(config, path, false)
+    }
+
+    /// Same as `open', for a chunk that may have been cut short,
+    /// which is read up to where it was cut
+    pub(crate) fn recover(config: &Config, path: &Path) -> Result<Self, Error> {
+        Self::decrypt(config, path, true)
+    }
+
+    fn decrypt(config: &Config, path: &Path, partial: bool) -> Result<Self, Error> {
+        if !is_encrypted(path)? {
+            return Ok(Self {
+                path: path.to_path_buf(),
+                memory: None,
+                temporary: false,
+            });
+        }
+        let master = master_key(config)?.ok_or(NoKey)?;
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let (mut memory, memory_path) = memory_file(name)?;
+        decrypt_stream(
+            &master,
+            BufReader::new(File::open(path)?),
+            &mut memory,
+            partial,
+        )?;
+        Ok(Self {
+            path: memory_path,
+            memory: Some(memory),
+            temporary: false,
+        })
+    }
+
+    /// Same as `open', for a temporary copy of a chunk, which is
+    /// removed once it's no longer needed
+    pub(crate) fn open_temporary(config: &Config, path: PathBuf) -> Result<Self, Error> {
+        let plain = Self::open(config, &path);
+        match plain {
+            Ok(plain) if !plain.is_temporary() => Ok(Self {
+                path,
+                memory: None,
+                temporary: true,
+            }),
+            other => {
+                let _ = std::fs::remove_file(&path);
+                other
+            }
+        }
+    }
+
+    /// An empty file to write plain media that isn't kept to.  It's in
+    /// memory when chunks are encrypted, so the media never reaches
+    /// the disk in the clear, and a temporary file otherwise.
+    pub(crate) fn scratch(config: &Config, name: &str) -> Result<Self, Error> {
+        if config.encryption.is_some() {
+            let (memory, path) = memory_file(name)?;
+            return Ok(Self {
+                path,
+                memory: Some(memory),
+                temporary: false,
+            });
+        }
+        let dir = config.tmp_path().join("scratch");
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            path: dir.join(format!(
+                "{}-{:08x}-{}",
+                record::now_ms(),
+                rand::random::<u32>(),
+                name
+            )),
+            memory: None,
+            temporary: true,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the plain media goes away along with this, rather
+    /// than being the chunk itself
+    pub(crate) fn is_temporary(&self) -> bool {
+        self.temporary || self.memory.is_some()
+    }
+}
+
+impl Drop for PlainFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn cipher(key: &[u8]) -> Aes256Gcm {
+    Aes256Gcm::new_from_slice(key).expect("key of the wrong size")
+}
+
+fn seal(msg: &[u8]) -> Payload {
+    Payload { msg, aad: MAGIC }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// More than two records' worth of media that isn't all alike
+    fn media() -> Vec<u8> {
+        (0..2 * RECORD_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    /// `plain' sealed by a `ChunkWriter', finished or not
+    fn sealed(master: &MasterKey, plain: &[u8], finish: bool) -> Vec<u8> {
+        let mut writer = ChunkWriter::new(master, Vec::new()).unwrap();
+        writer.write_all(plain).unwrap();
+        if finish {
+            writer.finish().unwrap()
+        } else {
+            writer.inner
+        }
+    }
+
+    fn opened(master: &MasterKey, data: &[u8], partial: bool) -> Result<Vec<u8>, Error> {
+        let mut plain = Vec::new();
+        decrypt_stream(master, data, &mut plain, partial)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn chunks_decrypt_to_what_was_written() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let plain = media();
+        let data = sealed(&master, &plain, true);
+        assert!(is_encrypted_data(&data));
+        assert_eq!(opened(&master, &data, false).unwrap(), plain);
+    }
+
+    #[test]
+    fn whole_files_decrypt_as_streams() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let data = encrypt(&master, b"thumbnail").unwrap();
+        assert_eq!(opened(&master, &data, false).unwrap(), b"thumbnail");
+    }
+
+    #[test]
+    fn empty_chunks_decrypt_empty() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let data = sealed(&master, &[], true);
+        assert!(opened(&master, &data, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unfinished_chunks_are_truncated() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let plain = media();
+        let data = sealed(&master, &plain, false);
+        let err = opened(&master, &data, false).unwrap_err();
+        assert!(err.downcast_ref::<Truncated>().is_some());
+        assert_eq!(opened(&master, &data, true).unwrap(), plain);
+    }
+
+    #[test]
+    fn chunks_cut_within_a_record_keep_the_records_before() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let plain = media();
+        let data = sealed(&master, &plain, true);
+        let cut = &data[..data.len() - 500];
+        let err = opened(&master, cut, false).unwrap_err();
+        assert!(err.downcast_ref::<Truncated>().is_some());
+        assert_eq!(
+            opened(&master, cut, true).unwrap(),
+            &plain[..2 * RECORD_SIZE]
+        );
+    }
+
+    #[test]
+    fn tampered_chunks_dont_decrypt() {
+        let master = MasterKey([7; KEY_SIZE]);
+        let mut data = sealed(&master, &media(), true);
+        let last = data.len() - 100;
+        data[last] ^= 1;
+        let err = opened(&master, &data, true).unwrap_err();
+        assert!(err.downcast_ref::<DecryptError>().is_some());
+    }
+
+    #[test]
+    fn chunks_dont_decrypt_with_another_key() {
+        let data = sealed(&MasterKey([7; KEY_SIZE]), &media(), true);
+        let err = opened(&MasterKey([8; KEY_SIZE]), &data, true).unwrap_err();
+        assert!(err.downcast_ref::<DecryptError>().is_some());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::catalog::{Chunk, SharedCatalog};
+use crate::model::Config;
+use crate::record;
+
+/// Name of the event log, written under `storage.path'
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// The event log is written by the link to the server and read by
+/// the HTTP API
+pub(crate) type SharedEventLog = Arc<Mutex<EventLog>>;
+
+/// What `trigger' is set to for bookmarks
+pub(crate) const BOOKMARK: &str = "bookmark";
+
+/// Clip a device recorded in event mode, or bookmark a peer put on
+/// its timeline.  Times are in milliseconds since the UNIX epoch,
+/// and bookmarks start and end at the same time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Event {
+    pub(crate) device: String,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    /// What made the device record it, `motion' or `manual', or
+    /// `bookmark' for bookmarks
+    pub(crate) trigger: String,
+    /// What the peer that put the bookmark wrote about it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+}
+
+impl Event {
+    pub(crate) fn is_bookmark(&self) -> bool {
+        self.trigger == BOOKMARK
+    }
+
+    /// Bookmarks overlap the chunk they fall in
+    pub(crate) fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && self.end.max(self.start + 1) > start
+    }
+}
+
+/// Every event reported by the devices, kept in memory and backed by
+/// a file in which each one is appended as a JSON line
+pub(crate) struct EventLog {
+    file: File,
+    events: Vec<Event>,
+}
+
+impl EventLog {
+    pub(crate) fn open(config: &Config) -> Result<Self, Error> {
+        std::fs::create_dir_all(&config.storage.path)?;
+        let path = Path::new(&config.storage.path).join(EVENTS_FILE);
+        let mut events = vec![];
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                match serde_json::from_str::<Event>(&line) {
+                    Ok(event) => events.push(event),
+                    Err(err) => warn!("Skipping broken line in {:?}: {}", path, err),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!("Event log loaded with {} events", events.len());
+        Ok(Self { file, events })
+    }
+
+    pub(crate) fn add(&mut self, event: Event) -> Result<(), Error> {
+        writeln!(self.file, "{}", serde_json::to_string(&event)?)?;
+        self.events.push(event);
+        Ok(())
+    }
+
+    /// Events of a device overlapping a time range, oldest first
+    pub(crate) fn range(&self, device: &str, start: u64, end: u64) -> Vec<&Event> {
+        let mut events = self
+            .events
+            .iter()
+            .filter(|e| e.device == device && e.overlaps(start, end))
+            .collect::<Vec<&Event>>();
+        events.sort_by_key(|e| e.start);
+        events
+    }
+}
+
+/// Acts on the messages other peers send through the server
+pub(crate) struct Events {
+    config: Config,
+    catalog: SharedCatalog,
+    log: SharedEventLog,
+    /// When the motion each device is still seeing started
+    motion: HashMap<String, u64>,
+}
+
+impl Events {
+    pub(crate) fn new(config: Config, catalog: SharedCatalog, log: SharedEventLog) -> Self {
+        Self {
+            config,
+            catalog,
+            log,
+            motion: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn handle(&mut self, envelope: protocol::Envelope) {
+        // Bookmarks come from viewers, everything else from devices
+        if let protocol::Message::Bookmark {
+            device,
+            timestamp,
+            label,
+        } = envelope.message
+        {
+            self.bookmark(device, timestamp, label);
+            return;
+        }
+
+        // The server stamps every message with the JID the sender
+        // authenticated as, so a device can only flag its own chunks
+        let device = envelope.from_jid;
+        if !self.config.is_device(&device) {
+            return;
+        }
+        let flagged = match envelope.message {
+            protocol::Message::MotionStart { timestamp } => {
+                debug!("Motion on {} since {}", device, timestamp);
+                self.motion.insert(device.clone(), timestamp);
+                flag(&self.catalog, &device, timestamp, timestamp + 1)
+            }
+            protocol::Message::MotionStop { timestamp } => {
+                let start = self.motion.remove(&device).unwrap_or(timestamp);
+                flag(&self.catalog, &device, start, timestamp.max(start + 1))
+            }
+            // Clips are usually uploaded after they're reported, the
+            // chunks that aren't there yet are flagged on upload
+            protocol::Message::EventClip {
+                start,
+                end,
+                trigger,
+            } => {
+                info!("Clip from {} to {} recorded by {}", start, end, device);
+                let event = Event {
+                    device: device.clone(),
+                    start,
+                    end,
+                    trigger,
+                    label: None,
+                };
+                match self.log.lock().unwrap().add(event) {
+                    Ok(()) => flag(&self.catalog, &device, start, end.max(start + 1)),
+                    Err(err) => Err(err),
+                }
+            }
+            // Motion a device didn't get to report the end of lasts
+            // until it's gone
+            protocol::Message::PeerOffline => match self.motion.remove(&device) {
+                Some(start) => flag(&self.catalog, &device, start, record::now_ms()),
+                None => Ok(0),
+            },
+            _ => Ok(0),
+        };
+        match flagged {
+            Ok(0) => {}
+            Ok(count) => info!("Flagged {} chunks of {} as events", count, device),
+            Err(err) => error!("Can't flag chunks of {} as events: {}", device, err),
+        }
+    }
+
+    /// Keep a bookmark and pin the chunk it falls in.  Chunks that
+    /// aren't there yet, like the ones still in the local buffer of
+    /// the device, are pinned on upload.
+    fn bookmark(&mut self, device: String, timestamp: u64, label: String) {
+        if !self.config.is_device(&device) {
+            return;
+        }
+        info!("Bookmark on {} at {}: {}", device, timestamp, label);
+        let event = Event {
+            device: device.clone(),
+            start: timestamp,
+            end: timestamp,
+            trigger: BOOKMARK.to_string(),
+            label: Some(label),
+        };
+        let pinned = match self.log.lock().unwrap().add(event) {
+            Ok(()) => pin(&self.catalog, &device, timestamp, timestamp + 1),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = pinned {
+            error!("Can't pin bookmarked chunk of {}: {}", device, err);
+        }
+    }
+}
+
+/// Flag the chunks of a device overlapping a time range as events,
+/// so the retention policies keep them
+pub(crate) fn flag(
+    catalog: &SharedCatalog,
+    device: &str,
+    start: u64,
+    end: u64,
+) -> Result<usize, Error> {
+    mark(catalog, device, start, end, |chunk| &mut chunk.event)
+}
+
+/// Pin the chunks of a device overlapping a time range, so they're
+/// never deleted
+pub(crate) fn pin(
+    catalog: &SharedCatalog,
+    device: &str,
+    start: u64,
+    end: u64,
+) -> Result<usize, Error> {
+    mark(catalog, device, start, end, |chunk| &mut chunk.pinned)
+}
+
+fn mark(
+    catalog: &SharedCatalog,
+    device: &str,
+    start: u64,
+    end: u64,
+    field: fn(&mut Chunk) -> &mut bool,
+) -> Result<usize, Error> {
+    let mut catalog = catalog.lock().unwrap();
+    let chunks = catalog
+        .range(device, start, end)
+        .into_iter()
+        .cloned()
+        .filter_map(|mut chunk| {
+            if *field(&mut chunk) {
+                None
+            } else {
+                Some(chunk)
+            }
+        })
+        .collect::<Vec<Chunk>>();
+    let count = chunks.len();
+    for mut chunk in chunks {
+        *field(&mut chunk) = true;
+        catalog.put(chunk)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::thread;
+use std::time::Duration;
+
+use serde_derive::Serialize;
+
+use crate::catalog::{Catalog, Chunk, ChunkState, SharedCatalog};
+use crate::link::Link;
+use crate::model::Config;
+use crate::record;
+
+/// Stretch of time in which a device recorded nothing, in
+/// milliseconds since the UNIX epoch
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Gap {
+    pub(crate) start: u64,
+    /// Not set while the device still isn't recording
+    pub(crate) end: Option<u64>,
+}
+
+/// Up to when a chunk has footage.  Chunks still being written have
+/// footage up to now, and quarantined ones only at the time they
+/// started.
+fn footage_end(chunk: &Chunk, now: u64) -> u64 {
+    match (chunk.end, chunk.state) {
+        (Some(end), _) => end,
+        (None, ChunkState::Recording) => now,
+        (None, _) => chunk.start,
+    }
+}
+
+/// Gaps in the timeline of a device, oldest first.  Chunks deleted
+/// by the retention policies still count, since the device was
+/// recording when they were written.  There's no gap before the
+/// first chunk of a device.
+pub(crate) fn find(config: &Config, catalog: &Catalog, device: &str, now: u64) -> Vec<Gap> {
+    let threshold = config.gaps.threshold * 1000;
+    let mut gaps = vec![];
+    let mut covered: Option<u64> = None;
+    for chunk in catalog.timeline(device) {
+        if let Some(covered) = covered {
+            if chunk.start > covered + threshold {
+                gaps.push(Gap {
+                    start: covered,
+                    end: Some(chunk.start),
+                });
+            }
+        }
+        let end = footage_end(chunk, now);
+        covered = Some(covered.map_or(end, |c| c.max(end)));
+    }
+    if let Some(covered) = covered {
+        if now > covered + threshold {
+            gaps.push(Gap {
+                start: covered,
+                end: None,
+            });
+        }
+    }
+    gaps
+}
+
+/// Latest time a device has footage for
+pub(crate) fn last_recorded(catalog: &Catalog, device: &str, now: u64) -> Option<u64> {
+    catalog
+        .timeline(device)
+        .map(|chunk| footage_end(chunk, now))
+        .max()
+}
+
+/// Watch for devices that stop recording, in its own thread.  Each
+/// outage is logged once when it starts and once when it ends, and
+/// also sent to the peers connected to the server when there's a
+/// link to it and `gaps.notify' is set.
+pub(crate) fn spawn(
+    config: Config,
+    catalog: SharedCatalog,
+    link: Option<Link>,
+) -> thread::JoinHandle<()> {
+    let link = link.filter(|_| config.gaps.notify);
+    thread::spawn(move || {
+        let threshold = config.gaps.threshold * 1000;
+        // When the outage of each device that isn't recording started
+        let mut outages: HashMap<String, u64> = HashMap::new();
+        loop {
+            let now = record::now_ms();
+            for device in config.devices() {
+                let catalog = catalog.lock().unwrap();
+                let down =
+                    last_recorded(&catalog, device, now).filter(|last| now > last + threshold);
+
+                if let Some(since) = outages.get(device).cloned() {
+                    if down != Some(since) {
+                        let resumed = catalog
+                            .timeline(device)
+                            .map(|chunk| chunk.start)
+                            .find(|start| *start > since)
+                            .unwrap_or(now);
+                        info!("Device {} is recording again", device);
+                        outages.remove(device);
+                        notify(&link, device, since, Some(resumed));
+                    }
+                }
+                if let Some(since) = down {
+                    if !outages.contains_key(device) {
+                        warn!("Device {} hasn't recorded anything since {}", device, since);
+                        outages.insert(device.clone(), since);
+                        notify(&link, device, since, None);
+                    }
+                }
+            }
+            thread::sleep(Duration::from_secs(config.gaps.interval));
+        }
+    })
+}
+
+fn notify(link: &Option<Link>, device: &str, start: u64, end: Option<u64>) {
+    if let Some(link) = link {
+        link.broadcast(protocol::Message::RecordingGap {
+            device: device.to_string(),
+            start,
+            end,
+        });
+    }
+}
+
+/// Continuity of the recordings of every device in the Prometheus
+/// text format
+pub(crate) fn metrics(config: &Config, catalog: &Catalog, now: u64) -> String {
+    let threshold = config.gaps.threshold * 1000;
+    let mut recording = String::new();
+    let mut last = String::new();
+    let mut count = String::new();
+    let mut seconds = String::new();
+
+    for device in config.devices() {
+        let latest = last_recorded(catalog, device, now);
+        let up = latest.map_or(false, |latest| now <= latest + threshold);
+        let _ = writeln!(
+            recording,
+            "storage_recording{{device=\"{}\"}} {}",
+            device, up as u8
+        );
+        if let Some(latest) = latest {
+            let _ = writeln!(
+                last,
+                "storage_last_recorded_timestamp_seconds{{device=\"{}\"}} {}",
+                device,
+                latest / 1000,
+            );
+        }
+        let gaps = find(config, catalog, device, now);
+        let total: u64 = gaps
+            .iter()
+            .map(|gap| gap.end.unwrap_or(now) - gap.start)
+            .sum();
+        let _ = writeln!(
+            count,
+            "storage_gaps{{device=\"{}\"}} {}",
+            device,
+            gaps.len()
+        );
+        let _ = writeln!(
+            seconds,
+            "storage_gap_seconds{{device=\"{}\"}} {}",
+            device,
+            total / 1000,
+        );
+    }
+
+    format!(
+        "# HELP storage_recording Whether the device recorded anything lately\n\
+         # TYPE storage_recording gauge\n{}\
+         # HELP storage_last_recorded_timestamp_seconds Latest time the device has footage for\n\
+         # TYPE storage_last_recorded_timestamp_seconds gauge\n{}\
+         # HELP storage_gaps Gaps in the timeline of the device\n\
+         # TYPE storage_gaps gauge\n{}\
+         # HELP storage_gap_seconds Time the gaps in the timeline of the device add up to\n\
+         # TYPE storage_gap_seconds gauge\n{}",
+        recording, last, count, seconds,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, DEVICE};
+
+    fn chunk(start: u64, end: Option<u64>, state: ChunkState) -> Chunk {
+        let mut chunk = Chunk::new(DEVICE, &format!("{}.mp4", start), start);
+        chunk.end = end;
+        chunk.state = state;
+        chunk
+    }
+
+    /// Gaps in the timeline of the chunks, with a threshold of 5
+    /// seconds
+    fn gaps(name: &str, chunks: Vec<Chunk>, now: u64) -> Vec<(u64, Option<u64>)> {
+        let dir = testing::scratch_dir(name);
+        let config = testing::config(&dir, "[gaps]\nthreshold = 5");
+        let mut catalog = Catalog::open(&config).unwrap();
+        for chunk in chunks {
+            catalog.put(chunk).unwrap();
+        }
+        find(&config, &catalog, DEVICE, now)
+            .into_iter()
+            .map(|gap| (gap.start, gap.end))
+            .collect()
+    }
+
+    #[test]
+    fn pauses_longer_than_the_threshold_are_gaps() {
+        let chunks = vec![
+            chunk(100_000, Some(110_000), ChunkState::Complete),
+            chunk(113_000, Some(120_000), ChunkState::Complete),
+            chunk(200_000, Some(210_000), ChunkState::Deleted),
+            chunk(300_000, None, ChunkState::Recording),
+        ];
+        assert_eq!(
+            gaps("gaps-pauses", chunks, 400_000),
+            vec![(120_000, Some(200_000)), (210_000, Some(300_000))],
+        );
+    }
+
+    #[test]
+    fn devices_that_stopped_have_an_open_gap() {
+        let chunks = vec![
+            chunk(100_000, Some(110_000), ChunkState::Complete),
+            chunk(150_000, None, ChunkState::Quarantined),
+        ];
+        assert_eq!(
+            gaps("gaps-stopped", chunks, 400_000),
+            vec![(110_000, Some(150_000)), (150_000, None)],
+        );
+    }
+
+    #[test]
+    fn devices_without_chunks_have_no_gaps() {
+        assert!(gaps("gaps-none", vec![], 400_000).is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::thread;
+use std::time::Duration;
+
+use awc::ws;
+use failure::{format_err, Error};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{SinkExt, StreamExt};
+use openssl::ssl::{SslConnector, SslMethod};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::archive;
+use crate::catalog::SharedCatalog;
+use crate::events::{Events, SharedEventLog};
+use crate::model::{Config, ConfigServer};
+
+/// Seconds to wait before connecting again after losing the server
+const RECONNECT_INTERVAL: u64 = 10;
+
+/// What storage tells the server it can do
+const CAPABILITIES: &[&str] = &["store:video"];
+
+/// Token the server hands out at `/auth'
+#[derive(Deserialize)]
+struct Token {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct Credential<'a> {
+    jid: &'a str,
+    password: &'a str,
+}
+
+/// Connection to the protocol server.  Messages are queued while the
+/// server can't be reached and sent once it's back.
+#[derive(Clone)]
+pub(crate) struct Link {
+    jid: String,
+    queue: mpsc::UnboundedSender<protocol::Envelope>,
+}
+
+impl Link {
+    /// Send a message to every peer connected to the server
+    pub(crate) fn broadcast(&self, message: protocol::Message) {
+        let envelope = protocol::Envelope {
+            from_jid: self.jid.clone(),
+            to_jid: String::new(),
+            message,
+        };
+        if self.queue.unbounded_send(envelope).is_err() {
+            warn!("Link to the server is gone");
+        }
+    }
+}
+
+/// Keep storage connected to the server in its own thread, when one
+/// is configured
+pub(crate) fn spawn(config: &Config, catalog: SharedCatalog, log: SharedEventLog) -> Option<Link> {
+    let server = config.server.clone()?;
+    let (queue, mut pending) = mpsc::unbounded();
+    let link = Link {
+        jid: server.jid.clone(),
+        queue,
+    };
+    let mut events = Events::new(config.clone(), catalog, log);
+    thread::spawn(move || {
+        archive::block_on(async move {
+            loop {
+                match serve(&server, &mut pending, &mut events).await {
+                    Ok(()) => info!("Server {} closed the connection", server.url),
+                    Err(err) => warn!("Connection to server {} lost: {}", server.url, err),
+                }
+                actix_rt::time::sleep(Duration::from_secs(RECONNECT_INTERVAL)).await;
+            }
+        })
+    });
+    Some(link)
+}
+
+/// The HTTP endpoints, like `/auth', sit next to `/ws' on the server
+fn server_url(url: &str, path: &str) -> String {
+    let base = url.trim_end_matches('/').trim_end_matches("/ws");
+    let base = if let Some(rest) = base.strip_prefix("wss://") {
+        format!("https://{}", rest)
+    } else if let Some(rest) = base.strip_prefix("ws://") {
+        format!("http://{}", rest)
+    } else {
+        base.to_string()
+    };
+    format!("{}{}", base, path)
+}
+
+/// Trade `server.secret' for a token.  The server only checks it
+/// when the connection opens, so a new one is taken every time
+/// storage connects, the way capture refreshes its own.
+async fn login(client: &awc::Client, server: &ConfigServer) -> Result<String, Error> {
+    let mut response = client
+        .post(server_url(&server.url, "/auth"))
+        .send_json(&Credential {
+            jid: &server.jid,
+            password: &server.secret,
+        })
+        .await
+        .map_err(|e| format_err!("Can't reach the server: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format_err!(
+            "Server didn't take the secret: {}",
+            response.status()
+        ));
+    }
+    let token = response
+        .json::<Token>()
+        .await
+        .map_err(|e| format_err!("Can't read token: {}", e))?;
+    Ok(token.token)
+}
+
+/// Log in, connect to the server, announce storage and then send
+/// what's queued and act on what comes in until the connection drops
+async fn serve(
+    server: &ConfigServer,
+    pending: &mut mpsc::UnboundedReceiver<protocol::Envelope>,
+    events: &mut Events,
+) -> Result<(), Error> {
+    let mut ssl = SslConnector::builder(SslMethod::tls())?;
+    ssl.set_ca_file(&server.cacert)?;
+    let connector = awc::Connector::new()
+        .timeout(Duration::from_secs(15))
+        .openssl(ssl.build());
+    let client = awc::Client::builder().connector(connector).finish();
+    let token = login(&client, server).await?;
+    let (_, framed) = client
+        .ws(&server.url)
+        .bearer_auth(token)
+        .connect()
+        .await
+        .map_err(|e| format_err!("Can't connect: {}", e))?;
+    info!("Connected to server {} as {}", server.url, server.jid);
+    let (mut sink, mut stream) = framed.split();
+
+    let caps = protocol::Envelope {
+        from_jid: server.jid.clone(),
+        to_jid: String::new(),
+        message: protocol::Message::PeerCaps(CAPABILITIES.iter().map(|c| c.to_string()).collect()),
+    };
+    send(&mut sink, &caps).await?;
+
+    loop {
+        match future::select(stream.next(), pending.next()).await {
+            Either::Left((frame, _)) => match frame {
+                Some(Ok(ws::Frame::Ping(data))) => sink
+                    .send(ws::Message::Pong(data))
+                    .await
+                    .map_err(|e| format_err!("{}", e))?,
+                Some(Ok(ws::Frame::Text(text))) => {
+                    match serde_json::from_slice::<protocol::Envelope>(&text) {
+                        Ok(envelope) => events.handle(envelope),
+                        Err(err) => warn!("Can't read message from server: {}", err),
+                    }
+                }
+                Some(Ok(ws::Frame::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(format_err!("{}", err)),
+            },
+            Either::Right((Some(envelope), _)) => send(&mut sink, &envelope).await?,
+            Either::Right((None, _)) => return Ok(()),
+        }
+    }
+}
+
+async fn send<S>(sink: &mut S, envelope: &protocol::Envelope) -> Result<(), Error>
+where
+    S: futures::Sink<ws::Message, Error = ws::ProtocolError> + Unpin,
+{
+    let text = serde_json::to_string(envelope)?;
+    sink.send(ws::Message::Text(text.into()))
+        .await
+        .map_err(|e| format_err!("{}", e))
+}
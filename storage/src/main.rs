@@ -5,20 +5,45 @@ extern crate glib;
 #[macro_use]
 extern crate failure_derive;
 extern crate gstreamer as gst;
+extern crate gstreamer_app as gst_app;
+extern crate gstreamer_pbutils as gst_pbutils;
 extern crate gstreamer_rtsp as gst_rtsp;
 extern crate gstreamer_rtsp_server as gst_rtsp_server;
 extern crate gstreamer_rtsp_server_sys as gst_rtsp_server_sys;
-
-use serde_derive::Deserialize;
+#[macro_use]
+extern crate log;
 
 use failure::Error;
 use std::env;
 use std::path::Path;
 
-use gst_rtsp::*;
 use gst_rtsp_server::prelude::*;
 use gst_rtsp_server::*;
 
+mod api;
+mod archive;
+mod auth;
+mod catalog;
+mod crypto;
+mod events;
+mod gaps;
+mod link;
+mod model;
+mod play;
+mod playback;
+mod record;
+mod replication;
+mod retention;
+#[cfg(test)]
+mod testing;
+mod thumbnail;
+mod verify;
+
+use catalog::Catalog;
+use events::EventLog;
+use model::Config;
+use std::sync::{Arc, Mutex};
+
 // ---- Custom Exceptions ----
 
 #[derive(Debug, Fail)]
@@ -26,48 +51,72 @@ use gst_rtsp_server::*;
 struct NoMountPoints;
 
 #[derive(Debug, Fail)]
-#[fail(display = "Usage: {} CONFIG-FILE", _0)]
+#[fail(
+    display = "Usage: {} CONFIG-FILE [decrypt INPUT OUTPUT | verify DEVICE]",
+    _0
+)]
 struct UsageError(String);
 
-// ---- Configuration Structure ----
-
-#[derive(Clone, Debug, Deserialize)]
-struct ConfigHTTP {
-    host: String,
-    port: u16,
-}
+#[derive(Debug, Fail)]
+#[fail(display = "Media has no element")]
+pub(crate) struct NoMediaElement;
 
-#[derive(Clone, Debug, Deserialize)]
-struct Config {
-    http: ConfigHTTP,
-}
+#[derive(Debug, Fail)]
+#[fail(display = "Could not find element {}", _0)]
+pub(crate) struct NoSuchElement(String);
 
 fn run(config: Config) -> Result<(), Error> {
     let main_loop = glib::MainLoop::new(None, false);
     let server = RTSPServer::new();
     let mounts = server.get_mount_points().ok_or(NoMountPoints)?;
-    let factory = RTSPMediaFactory::new();
 
     // Port numbers are passed as strings to the gst-rtsp server
     let port = config.http.port.to_string();
     server.set_property("service", &port).unwrap();
 
+    // Better to find out about a bad key before recording anything
+    crypto::master_key(&config)?;
+
+    // Whatever was left behind by a previous run is of no use
+    let _ = std::fs::remove_dir_all(config.tmp_path());
 
-    // Finish configuring media factory
-    let line = "rtph264depay name=depay0 ! h264parse ! splitmuxsink location=video%02d.mp4 max-size-time=10000000000";
-    factory.set_transport_mode(RTSPTransportMode::RECORD);
-    factory.set_profiles(RTSPProfile::AVP | RTSPProfile::AVPF);
-    factory.set_launch(line);
+    // Index of the chunks that are already on disk
+    let catalog = Arc::new(Mutex::new(Catalog::open(&config)?));
 
-    // Mounting point for the stream
-    mounts.add_factory("/test", &factory);
+    // Mounting points for the streams of each device, both coming
+    // from the devices and going to RTSP clients watching them live
+    let relay = play::LiveRelay::default();
+    record::mount(&config, &mounts, &catalog, &relay)?;
+    relay.mount(&config, &mounts);
+
+    // Devices have to show a token from the server before pushing,
+    // and viewers before watching, when `[auth]' is set
+    record::authorize(&config, &server);
 
     let id = server.attach(None);
 
-    println!(
-        "Stream ready at rtsps://127.0.0.1:{}/test",
-        server.get_bound_port()
-    );
+    info!("RTSP server ready at port {}", server.get_bound_port());
+
+    // Old footage is deleted in the background
+    retention::spawn(config.clone(), catalog.clone());
+
+    // Finished chunks are copied to the peer and to the archive in
+    // the background
+    replication::spawn(config.clone(), catalog.clone());
+    archive::spawn(config.clone(), catalog.clone());
+
+    // Thumbnails are extracted as chunks are finished
+    thumbnail::spawn(config.clone(), catalog.clone());
+
+    // Devices that stop recording are reported to the peers
+    // connected to the server, and chunks in which devices saw motion
+    // or recorded clips for are flagged as events
+    let events = Arc::new(Mutex::new(EventLog::open(&config)?));
+    let link = link::spawn(&config, catalog.clone(), events.clone());
+    gaps::spawn(config.clone(), catalog.clone(), link);
+
+    // The HTTP API runs on its own thread with its own event loop
+    api::spawn(config, catalog, events, mounts);
 
     main_loop.run();
 
@@ -76,8 +125,15 @@ fn run(config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// Decrypt a chunk copied out of storage, so it can be played
+/// anywhere
+fn decrypt(config: Config, input: &str, output: &str) -> Result<(), Error> {
+    let master = crypto::master_key(&config)?.ok_or(crypto::NoKey)?;
+    crypto::decrypt_file(&master, Path::new(input), Path::new(output))
+}
+
 fn load_config(args: &Vec<String>) -> Result<Config, Error> {
-    if args.len() != 2 {
+    if args.len() < 2 {
         // Can't move on without the configuration file
         return Err(Error::from(UsageError(args[0].clone())));
     } else {
@@ -88,14 +144,26 @@ fn load_config(args: &Vec<String>) -> Result<Config, Error> {
         // TOML string that was read from it.
         let strcontent = contents.as_str();
         let config: Config = toml::from_str(strcontent)?;
+        config.check_devices()?;
         Ok(config)
     }
 }
 
 fn main() -> Result<(), Error> {
+    env_logger::init();
     gst::init()?;
 
     let args: Vec<String> = env::args().collect();
     let config: Config = load_config(&args)?;
-    run(config)
+    match args
+        .iter()
+        .skip(2)
+        .map(String::as_str)
+        .collect::<Vec<&str>>()[..]
+    {
+        [] => run(config),
+        ["decrypt", input, output] => decrypt(config, input, output),
+        ["verify", device] => verify::verify(&config, device),
+        _ => Err(Error::from(UsageError(args[0].clone()))),
+    }
 }
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Device JID {} isn't NAME@DOMAIN", _0)]
+pub(crate) struct BadDeviceJid(String);
+
+/// Where the RTSP server listens for the streams coming from the
+/// capture devices
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigHTTP {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+/// Where the HTTP API listens
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigAPI {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigStorage {
+    /// Directory under which each device gets its own directory of
+    /// chunks
+    pub(crate) path: String,
+    /// Duration of each chunk, in seconds
+    #[serde(default = "default_chunk_duration")]
+    pub(crate) chunk_duration: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigLocation {
+    pub(crate) devices: Vec<String>,
+    /// Days the footage of the devices in this location is kept for,
+    /// overrides `retention.keep_days'
+    #[serde(default)]
+    pub(crate) keep_days: Option<u64>,
+}
+
+/// Chunks are encrypted once they're finished when this is set
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigEncryption {
+    /// Hex encoded 256 bit master key, which wraps the key of each
+    /// chunk
+    pub(crate) key: String,
+}
+
+/// Where finished chunks are copied to, so they survive this box
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigReplication {
+    /// URL of the HTTP API of the peer storage
+    pub(crate) peer: String,
+    /// Token the peer expects, see `ConfigReplica'
+    pub(crate) token: String,
+    /// Names of the locations whose chunks are replicated, all of
+    /// them when not set
+    #[serde(default)]
+    pub(crate) locations: Option<Vec<String>>,
+    /// Seconds between each attempt at catching the peer up
+    #[serde(default = "default_replication_interval")]
+    pub(crate) interval: u64,
+}
+
+/// Set on storage that accepts chunks replicated from a peer
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigReplica {
+    /// Token peers present as `Authorization: Bearer TOKEN'
+    pub(crate) token: String,
+}
+
+/// Set when devices have to prove who they are with the tokens the
+/// server issues them
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigAuth {
+    /// Same as the server's `userauth.jwt_secret'
+    pub(crate) jwt_secret: String,
+}
+
+/// Key of the links that chain the chunks of each device.  Chunks
+/// aren't chained without it.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigChain {
+    /// Kept away from whoever can write the catalog, and the same on
+    /// replication peers
+    pub(crate) secret: String,
+}
+
+/// S3 compatible bucket finished chunks are archived to
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigArchive {
+    /// URL of the object storage, like `http://localhost:9000' for
+    /// MinIO
+    pub(crate) endpoint: String,
+    #[serde(default = "default_archive_region")]
+    pub(crate) region: String,
+    pub(crate) bucket: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    /// Address the bucket as `ENDPOINT/BUCKET' instead of
+    /// `BUCKET.ENDPOINT', as MinIO expects
+    #[serde(default)]
+    pub(crate) path_style: bool,
+    /// Hours after they're finished in which chunks are archived
+    #[serde(default)]
+    pub(crate) after_hours: u64,
+    /// Seconds between each look for chunks to archive
+    #[serde(default = "default_archive_interval")]
+    pub(crate) interval: u64,
+}
+
+/// Thumbnails extracted out of finished chunks, for scrubbing
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigThumbnails {
+    /// Seconds between each thumbnail within a chunk
+    #[serde(default = "default_thumbnail_interval")]
+    pub(crate) interval: u64,
+    /// Width of the thumbnails in pixels, the height follows the
+    /// aspect ratio of the video
+    #[serde(default = "default_thumbnail_width")]
+    pub(crate) width: u32,
+}
+
+/// Set to let Prometheus scrape `/metrics' with a token of its own
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigMetrics {
+    /// Token Prometheus presents as `Authorization: Bearer TOKEN'
+    pub(crate) token: String,
+}
+
+/// Protocol server storage connects to as a peer of its own, to send
+/// and receive notifications
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigServer {
+    /// WebSocket endpoint of the server, like `wss://HOST:PORT/ws'
+    pub(crate) url: String,
+    /// JID storage connects as
+    pub(crate) jid: String,
+    /// Secret traded for a token at the server's `/auth', whose
+    /// SHA-256 is under `credentials' there with the `storage' role
+    pub(crate) secret: String,
+    /// CA the certificate of the server is checked against
+    pub(crate) cacert: String,
+}
+
+/// How stretches of time without footage are found
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigGaps {
+    /// Seconds without footage before it counts as a gap
+    #[serde(default = "default_gap_threshold")]
+    pub(crate) threshold: u64,
+    /// Seconds between each look for devices that stopped recording
+    #[serde(default = "default_gap_interval")]
+    pub(crate) interval: u64,
+    /// Tell the peers connected to the server when a device stops
+    /// and starts recording again
+    #[serde(default = "default_gap_notify")]
+    pub(crate) notify: bool,
+}
+
+impl Default for ConfigGaps {
+    fn default() -> Self {
+        Self {
+            threshold: default_gap_threshold(),
+            interval: default_gap_interval(),
+            notify: default_gap_notify(),
+        }
+    }
+}
+
+/// What is deleted and when, so storage doesn't run out of space
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigRetention {
+    /// Seconds between each run of the retention policies
+    #[serde(default = "default_retention_interval")]
+    pub(crate) interval: u64,
+    /// Bytes the chunks of all devices can take together.  The
+    /// oldest chunks are deleted once they take more than that.
+    #[serde(default)]
+    pub(crate) maxspace: Option<u64>,
+    /// Days footage is kept for, unless the location or the device
+    /// say otherwise.  Footage is kept for as long as space allows
+    /// when no limit is set.
+    #[serde(default)]
+    pub(crate) keep_days: Option<u64>,
+    /// Never delete chunks flagged as events
+    #[serde(default = "default_keep_events")]
+    pub(crate) keep_events: bool,
+    /// Rules for single devices, by JID
+    #[serde(default)]
+    pub(crate) devices: HashMap<String, ConfigDeviceRetention>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ConfigDeviceRetention {
+    /// Days the footage of the device is kept for, overrides the
+    /// location's and the global `keep_days'
+    pub(crate) keep_days: Option<u64>,
+}
+
+impl Default for ConfigRetention {
+    fn default() -> Self {
+        Self {
+            interval: default_retention_interval(),
+            maxspace: None,
+            keep_days: None,
+            keep_events: default_keep_events(),
+            devices: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Config {
+    pub(crate) http: ConfigHTTP,
+    pub(crate) api: ConfigAPI,
+    pub(crate) storage: ConfigStorage,
+    pub(crate) locations: HashMap<String, ConfigLocation>,
+    pub(crate) auth: Option<ConfigAuth>,
+    pub(crate) chain: Option<ConfigChain>,
+    #[serde(default)]
+    pub(crate) retention: ConfigRetention,
+    pub(crate) encryption: Option<ConfigEncryption>,
+    pub(crate) replication: Option<ConfigReplication>,
+    pub(crate) replica: Option<ConfigReplica>,
+    pub(crate) archive: Option<ConfigArchive>,
+    pub(crate) thumbnails: Option<ConfigThumbnails>,
+    pub(crate) server: Option<ConfigServer>,
+    pub(crate) metrics: Option<ConfigMetrics>,
+    #[serde(default)]
+    pub(crate) gaps: ConfigGaps,
+}
+
+impl Config {
+    /// JIDs of all the devices allowed to record, regardless of
+    /// their location
+    pub(crate) fn devices(&self) -> impl Iterator<Item = &String> {
+        self.locations.values().flat_map(|l| l.devices.iter())
+    }
+
+    pub(crate) fn is_device(&self, jid: &str) -> bool {
+        self.devices().any(|d| d == jid)
+    }
+
+    /// Make sure the directory of each device can't be taken for one
+    /// of the others under `storage.path', like `tmp' or
+    /// `quarantine', or point outside of it
+    pub(crate) fn check_devices(&self) -> Result<(), BadDeviceJid> {
+        for jid in self.devices() {
+            let valid = match jid.split_once('@') {
+                Some((name, domain)) => !name.is_empty() && !domain.is_empty(),
+                None => false,
+            };
+            if !valid || jid.contains('/') || jid.contains('\0') {
+                return Err(BadDeviceJid(jid.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Days the footage of a device is kept for.  The most specific
+    /// rule wins: the device's, then its location's, then the global
+    /// one.
+    pub(crate) fn keep_days(&self, jid: &str) -> Option<u64> {
+        self.retention
+            .devices
+            .get(jid)
+            .and_then(|d| d.keep_days)
+            .or_else(|| {
+                self.locations
+                    .values()
+                    .filter(|l| l.devices.iter().any(|d| d == jid))
+                    .find_map(|l| l.keep_days)
+            })
+            .or(self.retention.keep_days)
+    }
+
+    /// Directory where the chunks of a device are written to
+    pub(crate) fn device_path(&self, jid: &str) -> PathBuf {
+        PathBuf::from(&self.storage.path).join(jid)
+    }
+
+    /// Directory where the chunks of a device that couldn't be
+    /// repaired are moved to, so they can still be looked at
+    pub(crate) fn quarantine_path(&self, jid: &str) -> PathBuf {
+        PathBuf::from(&self.storage.path)
+            .join("quarantine")
+            .join(jid)
+    }
+
+    /// Directory where the thumbnails of a device are written to
+    pub(crate) fn thumbnail_path(&self, jid: &str) -> PathBuf {
+        PathBuf::from(&self.storage.path)
+            .join("thumbnails")
+            .join(jid)
+    }
+
+    /// Directory for files that only live while storage is running,
+    /// like exports and HLS segments
+    pub(crate) fn tmp_path(&self) -> PathBuf {
+        PathBuf::from(&self.storage.path).join("tmp")
+    }
+}
+
+fn default_chunk_duration() -> u64 {
+    10
+}
+
+fn default_retention_interval() -> u64 {
+    300
+}
+
+fn default_keep_events() -> bool {
+    true
+}
+
+fn default_replication_interval() -> u64 {
+    30
+}
+
+fn default_archive_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_archive_interval() -> u64 {
+    300
+}
+
+fn default_thumbnail_interval() -> u64 {
+    5
+}
+
+fn default_thumbnail_width() -> u32 {
+    160
+}
+
+fn default_gap_threshold() -> u64 {
+    30
+}
+
+fn default_gap_interval() -> u64 {
+    10
+}
+
+fn default_gap_notify() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::testing;
+
+    #[test]
+    fn the_most_specific_keep_days_wins() {
+        // The first line goes to `locations.studio', which `extra'
+        // follows
+        let config = testing::config(
+            Path::new("/srv/ucam"),
+            "keep_days = 14\n\
+             [locations.lobby]\n\
+             devices = ['cam002@studio.loc', 'cam003@studio.loc']\n\
+             [retention]\n\
+             keep_days = 30\n\
+             [retention.devices.'cam003@studio.loc']\n\
+             keep_days = 2",
+        );
+        assert_eq!(config.keep_days("cam001@studio.loc"), Some(14));
+        assert_eq!(config.keep_days("cam002@studio.loc"), Some(30));
+        assert_eq!(config.keep_days("cam003@studio.loc"), Some(2));
+    }
+
+    #[test]
+    fn footage_is_kept_without_a_limit() {
+        let config = testing::config(Path::new("/srv/ucam"), "");
+        assert_eq!(config.keep_days("cam001@studio.loc"), None);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use glib::ToValue;
+use gst::prelude::*;
+use gst_rtsp_server::prelude::*;
+use gst_rtsp_server::*;
+
+use crate::crypto::PlainFile;
+use crate::model::Config;
+use crate::{NoMediaElement, NoSuchElement};
+
+/// Seconds a playback range stays mounted when nobody plays it
+const UNPLAYED_TIMEOUT: u32 = 60;
+
+/// Mount point where the live stream of a device is served
+pub(crate) fn live_path(jid: &str) -> String {
+    format!("/live/{}", jid)
+}
+
+/// Mount point where the recordings of a device within a time range
+/// are served
+pub(crate) fn playback_path(jid: &str, start: u64, end: u64) -> String {
+    format!("/playback/{}/{}-{}", jid, start, end)
+}
+
+/// Relays the stream each device pushes for recording to whoever is
+/// watching it live over RTSP
+#[derive(Clone, Default)]
+pub(crate) struct LiveRelay(Arc<Mutex<HashMap<String, Vec<Viewer>>>>);
+
+/// The source of a live media, it only gets data once a key frame
+/// comes along
+struct Viewer {
+    appsrc: gst_app::AppSrc,
+    started: bool,
+}
+
+impl LiveRelay {
+    /// Add one shared PLAY media factory per device.  All the RTSP
+    /// clients watching a device share the same media.
+    pub(crate) fn mount(&self, config: &Config, mounts: &RTSPMountPoints) {
+        for jid in config.devices() {
+            let factory = RTSPMediaFactory::new();
+            factory.set_shared(true);
+            factory.set_launch(
+                "appsrc name=src is-live=true format=time do-timestamp=true ! \
+                 h264parse ! rtph264pay name=pay0 pt=96 config-interval=-1",
+            );
+
+            let relay = self.clone();
+            let device = jid.clone();
+            factory.connect_media_configure(move |_factory, media| {
+                if let Err(err) = relay.configure_media(&device, media) {
+                    error!("Can't configure live stream of {}: {}", device, err);
+                }
+            });
+
+            mounts.add_factory(&live_path(jid), &factory);
+            info!("Live stream of {} at {}", jid, live_path(jid));
+        }
+    }
+
+    /// Start relaying what comes out of a recording media to the
+    /// live viewers of the device
+    pub(crate) fn attach(&self, jid: &str, appsink: &gst_app::AppSink) {
+        let relay = self.clone();
+        let device = jid.to_string();
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::new()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().ok_or(gst::FlowError::Eos)?;
+                    relay.push(&device, &sample);
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+    }
+
+    fn configure_media(&self, jid: &str, media: &RTSPMedia) -> Result<(), Error> {
+        let element = media.get_element().ok_or(NoMediaElement)?;
+        let appsrc = element
+            .downcast::<gst::Bin>()
+            .map_err(|_| NoMediaElement)?
+            .get_by_name("src")
+            .ok_or_else(|| NoSuchElement("src".to_string()))?
+            .downcast::<gst_app::AppSrc>()
+            .map_err(|_| NoSuchElement("src".to_string()))?;
+
+        self.0
+            .lock()
+            .unwrap()
+            .entry(jid.to_string())
+            .or_insert_with(Vec::new)
+            .push(Viewer {
+                appsrc: appsrc.clone(),
+                started: false,
+            });
+
+        // Stop feeding the media once the last client is gone
+        let relay = self.clone();
+        let device = jid.to_string();
+        media.connect_unprepared(move |_media| {
+            if let Some(viewers) = relay.0.lock().unwrap().get_mut(&device) {
+                viewers.retain(|viewer| viewer.appsrc != appsrc);
+            }
+        });
+        Ok(())
+    }
+
+    fn push(&self, jid: &str, sample: &gst::Sample) {
+        let buffer = match sample.get_buffer() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let mut relay = self.0.lock().unwrap();
+        let viewers = match relay.get_mut(jid) {
+            Some(viewers) if !viewers.is_empty() => viewers,
+            _ => return,
+        };
+
+        // The live media has a clock of its own, so the source stamps
+        // each buffer as it goes out
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::CLOCK_TIME_NONE);
+            buffer.set_dts(gst::CLOCK_TIME_NONE);
+        }
+        let keyframe = !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+        for viewer in viewers.iter_mut() {
+            if !viewer.started {
+                if !keyframe {
+                    continue;
+                }
+                if let Some(caps) = sample.get_caps() {
+                    viewer.appsrc.set_caps(Some(&caps.to_owned()));
+                }
+                viewer.started = true;
+            }
+            let _ = viewer.appsrc.push_buffer(buffer.clone());
+        }
+    }
+}
+
+/// A time range mounted for playback
+struct Mounted {
+    /// How many medias are playing it
+    medias: usize,
+    /// The chunks it plays, kept readable as plain media for as long
+    /// as the range is mounted
+    files: Vec<PlainFile>,
+}
+
+/// Time ranges mounted for playback.  A range is unmounted once its
+/// last media is gone, or when nobody plays it within
+/// `UNPLAYED_TIMEOUT' seconds of being mounted.
+#[derive(Clone)]
+pub(crate) struct Playbacks {
+    mounts: RTSPMountPoints,
+    mounted: Arc<Mutex<HashMap<String, Mounted>>>,
+}
+
+impl Playbacks {
+    pub(crate) fn new(mounts: RTSPMountPoints) -> Self {
+        Playbacks {
+            mounts,
+            mounted: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn is_mounted(&self, path: &str) -> bool {
+        self.mounted.lock().unwrap().contains_key(path)
+    }
+
+    /// Make the chunks of a device within a time range available over
+    /// RTSP at `playback_path'.  The chunks are
+    /// fetched from the archive and decrypted beforehand, so nothing
+    /// blocks the streaming threads of the media.  Clients can seek
+    /// within the range with the `Range' header of `PLAY'.  Asking for
+    /// a range that's still mounted reuses its mount point.
+    pub(crate) fn mount(&self, jid: &str, start: u64, end: u64, files: Vec<PlainFile>) {
+        let path = playback_path(jid, start, end);
+        {
+            let mut mounted = self.mounted.lock().unwrap();
+            if mounted.contains_key(&path) {
+                return;
+            }
+            mounted.insert(path.clone(), Mounted { medias: 0, files });
+        }
+
+        let factory = RTSPMediaFactory::new();
+        factory.set_launch("splitmuxsrc name=src ! h264parse ! rtph264pay name=pay0 pt=96");
+
+        let playbacks = self.clone();
+        let device = jid.to_string();
+        let mounted = path.clone();
+        factory.connect_media_configure(move |_factory, media| {
+            playbacks.started(&mounted);
+            let stopped = playbacks.clone();
+            let unprepared = mounted.clone();
+            media.connect_unprepared(move |_media| stopped.stopped(&unprepared));
+            if let Err(err) = configure_playback(playbacks.locations(&mounted), media) {
+                error!("Can't configure playback of {}: {}", device, err);
+            }
+        });
+
+        self.mounts.add_factory(&path, &factory);
+        info!("Recordings of {} mounted at {}", jid, path);
+
+        let playbacks = self.clone();
+        let unplayed = path.clone();
+        glib::timeout_add_seconds(UNPLAYED_TIMEOUT, move || {
+            playbacks.unmount_idle(&unplayed);
+            glib::Continue(false)
+        });
+    }
+
+    /// Where the files of the chunks mounted at a path can be read
+    fn locations(&self, path: &str) -> Vec<String> {
+        self.mounted
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|mounted| {
+                mounted
+                    .files
+                    .iter()
+                    .map(|file| file.path().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn started(&self, path: &str) {
+        if let Some(mounted) = self.mounted.lock().unwrap().get_mut(path) {
+            mounted.medias += 1;
+        }
+    }
+
+    fn stopped(&self, path: &str) {
+        if let Some(mounted) = self.mounted.lock().unwrap().get_mut(path) {
+            mounted.medias = mounted.medias.saturating_sub(1);
+        }
+        self.unmount_idle(path);
+    }
+
+    fn unmount_idle(&self, path: &str) {
+        let mut mounted = self.mounted.lock().unwrap();
+        if mounted.get(path).map(|m| m.medias) == Some(0) {
+            mounted.remove(path);
+            self.mounts.remove_factory(path);
+            info!("Unmounted {}", path);
+        }
+    }
+}
+
+/// Hand the files of the chunks mounted over to `splitmuxsrc'
+fn configure_playback(locations: Vec<String>, media: &RTSPMedia) -> Result<(), Error> {
+    let element = media.get_element().ok_or(NoMediaElement)?;
+    let src = element
+        .downcast::<gst::Bin>()
+        .map_err(|_| NoMediaElement)?
+        .get_by_name("src")
+        .ok_or_else(|| NoSuchElement("src".to_string()))?;
+
+    src.connect("format-location", false, move |_values| {
+        Some(locations.to_value())
+    })?;
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use failure::{format_err, Error};
+use glib::ToValue;
+use gst::prelude::*;
+
+use crate::catalog::Chunk;
+use crate::NoSuchElement;
+
+/// Stitch chunks together, in the given order, into a single MP4
+/// file.  The media is only remuxed, never encoded again, so the
+/// result starts and ends at chunk boundaries.  With `faststart', the
+/// headers go first, which takes a temporary file of its own.
+pub(crate) fn export(chunks: &[PathBuf], dest: &Path, faststart: bool) -> Result<(), Error> {
+    let pipeline = gst::parse_launch(&format!(
+        "splitmuxsrc name=src ! h264parse ! mp4mux faststart={} ! filesink name=sink",
+        faststart,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .expect("not a pipeline");
+
+    let files = chunks
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+    pipeline
+        .get_by_name("src")
+        .ok_or_else(|| NoSuchElement("src".to_string()))?
+        .connect("format-location", false, move |_values| {
+            Some(files.to_value())
+        })?;
+
+    set_location(&pipeline, "sink", dest)?;
+    run(&pipeline)
+}
+
+/// Remux a chunk into an MPEG-TS segment that HLS players take
+pub(crate) fn remux_ts(chunk: &Path, dest: &Path) -> Result<(), Error> {
+    let pipeline = gst::parse_launch(
+        "filesrc name=src ! qtdemux ! h264parse ! mpegtsmux ! filesink name=sink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .expect("not a pipeline");
+
+    set_location(&pipeline, "src", chunk)?;
+    set_location(&pipeline, "sink", dest)?;
+    run(&pipeline)
+}
+
+/// Remux a chunk that was never finished into a proper MP4 file,
+/// with as much of its media as can be read
+pub(crate) fn repair(chunk: &Path, dest: &Path) -> Result<(), Error> {
+    let pipeline =
+        gst::parse_launch("filesrc name=src ! qtdemux ! h264parse ! mp4mux ! filesink name=sink")?
+            .downcast::<gst::Pipeline>()
+            .expect("not a pipeline");
+
+    set_location(&pipeline, "src", chunk)?;
+    set_location(&pipeline, "sink", dest)?;
+    run(&pipeline)
+}
+
+/// Playlist for an HLS player to go through chunks one after the
+/// other.  Each segment starts its timestamps over, hence the
+/// discontinuity between them.
+pub(crate) fn hls_playlist(chunks: &[&Chunk], query: &str) -> String {
+    let target = chunks.iter().map(|c| chunk_duration(c)).max().unwrap_or(0);
+    let mut playlist = format!(
+        "#EXTM3U\n\
+         #EXT-X-VERSION:3\n\
+         #EXT-X-PLAYLIST-TYPE:VOD\n\
+         #EXT-X-TARGETDURATION:{}\n\
+         #EXT-X-MEDIA-SEQUENCE:0\n",
+        (target + 999) / 1000,
+    );
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i > 0 {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        playlist.push_str(&format!(
+            "#EXTINF:{:.3},\n{}.ts{}\n",
+            chunk_duration(chunk) as f64 / 1000.0,
+            chunk.start,
+            query,
+        ));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// Duration of a chunk in milliseconds, from its media if known
+fn chunk_duration(chunk: &Chunk) -> u64 {
+    chunk
+        .duration
+        .or_else(|| chunk.end.map(|end| end.saturating_sub(chunk.start)))
+        .unwrap_or(0)
+}
+
+fn set_location(pipeline: &gst::Pipeline, name: &str, path: &Path) -> Result<(), Error> {
+    pipeline
+        .get_by_name(name)
+        .ok_or_else(|| NoSuchElement(name.to_string()))?
+        .set_property("location", &path.to_string_lossy().to_string())?;
+    Ok(())
+}
+
+/// Play a pipeline until it's done
+pub(crate) fn run(pipeline: &gst::Pipeline) -> Result<(), Error> {
+    use gst::message::MessageView;
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .map_err(|_| format_err!("Can't start pipeline"))?;
+    let bus = pipeline
+        .get_bus()
+        .expect("Pipeline without bus. Shouldn't happen!");
+
+    let mut result = Err(format_err!("Pipeline stopped before finishing"));
+    for msg in bus.iter_timed(gst::CLOCK_TIME_NONE) {
+        match msg.view() {
+            MessageView::Eos(..) => {
+                result = Ok(());
+                break;
+            }
+            MessageView::Error(err) => {
+                result = Err(format_err!(
+                    "Error from {}: {} ({})",
+                    err.get_src()
+                        .map(|s| String::from(s.get_path_string()))
+                        .unwrap_or_else(|| String::from("None")),
+                    err.get_error(),
+                    err.get_debug().unwrap_or_else(|| String::from("None")),
+                ));
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    let _ = pipeline.set_state(gst::State::Null);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playlists_go_through_the_chunks_in_order() {
+        let mut first = Chunk::new("cam001@studio.loc", "1000.mp4", 1000);
+        first.duration = Some(9500);
+        let mut second = Chunk::new("cam001@studio.loc", "11000.mp4", 11000);
+        second.end = Some(21250);
+
+        let playlist = hls_playlist(&[&first, &second], "?token=abc");
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n\
+             #EXT-X-VERSION:3\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXT-X-TARGETDURATION:11\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXTINF:9.500,\n\
+             1000.ts?token=abc\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXTINF:10.250,\n\
+             11000.ts?token=abc\n\
+             #EXT-X-ENDLIST\n",
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::{format_err, Error};
+use glib::translate::{from_glib_none, ToGlibPtr};
+use glib::ToValue;
+use gst::prelude::*;
+use gst_rtsp::*;
+use gst_rtsp_server::prelude::*;
+use gst_rtsp_server::*;
+
+use crate::auth;
+use crate::catalog::{self, Chunk, ChunkState, SharedCatalog};
+use crate::crypto::{self, MasterKey};
+use crate::model::Config;
+use crate::play::LiveRelay;
+use crate::{NoMediaElement, NoSuchElement};
+
+/// Extension of the chunk files written by `splitmuxsink`
+const CHUNK_EXTENSION: &str = "mp4";
+
+/// Most bytes read out of the pipe an encrypted chunk is written
+/// through at once
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Threads encrypting the chunks being written, by the location the
+/// muxer writes them to
+type Encryptors = Arc<Mutex<HashMap<String, thread::JoinHandle<Result<(), Error>>>>>;
+
+/// Duration of each fragment within a chunk, in milliseconds.  That's
+/// at most how much media is lost if a chunk is never finished.
+const FRAGMENT_DURATION: u32 = 1000;
+
+/// Mount point where a device pushes its stream to
+pub(crate) fn record_path(jid: &str) -> String {
+    format!("/record/{}", jid)
+}
+
+/// Refuse the streams of devices that don't carry a valid token from
+/// the server in the query of the location they push to, as in
+/// `/record/<JID>?token=<TOKEN>'.  Pushing a stream starts with an
+/// ANNOUNCE, so that's the request checked.  Watching a stream, live
+/// or recorded, starts with a DESCRIBE, which needs the token of a
+/// viewer the same way.
+pub(crate) fn authorize(config: &Config, server: &RTSPServer) {
+    if config.auth.is_none() {
+        return;
+    }
+    let config = config.clone();
+    server.connect_client_connected(move |_server, client| {
+        let config = config.clone();
+        client.connect_pre_announce_request(move |_client, ctx| {
+            let (path, query) = request_target(ctx);
+            let device = path.strip_prefix("/record/").unwrap_or("");
+            let token = query
+                .as_ref()
+                .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("token=")));
+            if auth::is_authorized(&config, device, token) {
+                RTSPStatusCode::Ok
+            } else {
+                warn!("Refusing stream pushed to {}", path);
+                RTSPStatusCode::Unauthorized
+            }
+        });
+
+        let config = config.clone();
+        client.connect_pre_describe_request(move |_client, ctx| {
+            let (path, query) = request_target(ctx);
+            let token = query
+                .as_ref()
+                .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("token=")));
+            if auth::is_viewer(&config, token) {
+                RTSPStatusCode::Ok
+            } else {
+                warn!("Refusing to describe {}", path);
+                RTSPStatusCode::Unauthorized
+            }
+        });
+    });
+}
+
+/// Path and query of the URL a request is about
+fn request_target(ctx: &RTSPContext) -> (String, Option<String>) {
+    let ctx: *mut gst_rtsp_server_sys::GstRTSPContext = ctx.to_glib_none().0;
+    unsafe {
+        let uri = (*ctx).uri;
+        if uri.is_null() {
+            return (String::new(), None);
+        }
+        let path: Option<String> = from_glib_none((*uri).abspath as *const _);
+        let query: Option<String> = from_glib_none((*uri).query as *const _);
+        (path.unwrap_or_default(), query)
+    }
+}
+
+/// Chunks are named after the wall-clock time, in milliseconds, in
+/// which they started.  That keeps the listing of a device's
+/// directory sorted by time and lets chunks uploaded by the capture
+/// devices land in the same timeline.
+pub(crate) fn chunk_name(start: u64) -> String {
+    format!("{}.{}", start, CHUNK_EXTENSION)
+}
+
+/// Read the start time back from the name of a chunk, returns `None`
+/// for anything that isn't a chunk name
+pub(crate) fn parse_chunk_name(name: &str) -> Option<u64> {
+    let stem = name.strip_suffix(CHUNK_EXTENSION)?.strip_suffix('.')?;
+    if stem.is_empty() || !stem.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    stem.parse().ok()
+}
+
+/// Milliseconds since the UNIX epoch
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Add one RECORD media factory per device configured in any of the
+/// locations.  Each factory writes the chunks of its device into the
+/// device's own directory, and hands the stream over to the device's
+/// live viewers.
+pub(crate) fn mount(
+    config: &Config,
+    mounts: &RTSPMountPoints,
+    catalog: &SharedCatalog,
+    relay: &LiveRelay,
+) -> Result<(), Error> {
+    let master = crypto::master_key(config)?;
+    let pipes = config.tmp_path().join("pipes");
+    if master.is_some() {
+        std::fs::create_dir_all(&pipes)?;
+    }
+    for jid in config.devices() {
+        let dir = config.device_path(jid);
+        std::fs::create_dir_all(&dir)?;
+
+        let line = format!(
+            "rtph264depay name=depay0 ! h264parse config-interval=-1 ! tee name=t ! \
+             queue ! splitmuxsink name=mux max-size-time={} \
+             t. ! queue leaky=downstream ! appsink name=live sync=false max-buffers=64 drop=true",
+            config.storage.chunk_duration * 1_000_000_000,
+        );
+        let factory = RTSPMediaFactory::new();
+        factory.set_transport_mode(RTSPTransportMode::RECORD);
+        factory.set_profiles(RTSPProfile::AVP | RTSPProfile::AVPF);
+        factory.set_launch(&line);
+        // Finish the chunk being written when the device goes away
+        factory.set_eos_shutdown(true);
+
+        let device = jid.clone();
+        let catalog = catalog.clone();
+        let relay = relay.clone();
+        let master = master.clone();
+        let pipes = pipes.clone();
+        factory.connect_media_configure(move |_factory, media| {
+            if let Err(err) =
+                configure_media(&device, &dir, &pipes, &catalog, &relay, &master, media)
+            {
+                error!("Can't configure recording of {}: {}", device, err);
+            }
+        });
+
+        mounts.add_factory(&record_path(jid), &factory);
+        info!("Recording {} at {}", jid, record_path(jid));
+    }
+    Ok(())
+}
+
+/// Name each new chunk after the time it was opened, and keep the
+/// catalog posted about the chunks `splitmuxsink' opens and closes.
+/// With a master key, the muxer writes each chunk to a pipe in
+/// `pipes' instead, and what comes out of it is encrypted into the
+/// chunk file as it goes.
+fn configure_media(
+    device: &str,
+    dir: &Path,
+    pipes: &Path,
+    catalog: &SharedCatalog,
+    relay: &LiveRelay,
+    master: &Option<MasterKey>,
+    media: &RTSPMedia,
+) -> Result<(), Error> {
+    let element = media.get_element().ok_or(NoMediaElement)?;
+    let bin = element.downcast::<gst::Bin>().map_err(|_| NoMediaElement)?;
+    let mux = bin
+        .get_by_name("mux")
+        .ok_or_else(|| NoSuchElement("mux".to_string()))?;
+
+    let live = bin
+        .get_by_name("live")
+        .and_then(|e| e.downcast::<gst_app::AppSink>().ok())
+        .ok_or_else(|| NoSuchElement("live".to_string()))?;
+    relay.attach(device, &live);
+
+    // A fragmented MP4 stays readable up to its last fragment even if
+    // the muxer never gets to finish it
+    let muxer = gst::ElementFactory::make("mp4mux", None)?;
+    muxer.set_property("fragment-duration", &FRAGMENT_DURATION)?;
+    // There's no going back to the start of a pipe
+    if master.is_some() {
+        muxer.set_property("streamable", &true)?;
+    }
+    mux.set_property("muxer", &muxer)?;
+
+    let encryptors: Encryptors = Arc::new(Mutex::new(HashMap::new()));
+    let started = encryptors.clone();
+    let key = master.clone();
+    let device_name = device.to_string();
+    let dir: PathBuf = dir.to_path_buf();
+    let pipes: PathBuf = pipes.to_path_buf();
+    mux.connect("format-location", false, move |_values| {
+        let name = chunk_name(now_ms());
+        let location = match &key {
+            None => dir.join(&name),
+            Some(master) => {
+                let pipe = pipes.join(&name);
+                match encrypt_through_pipe(master, &pipe, &dir.join(&name)) {
+                    Ok(encryptor) => {
+                        let location = pipe.to_string_lossy().to_string();
+                        started.lock().unwrap().insert(location, encryptor);
+                        pipe
+                    }
+                    // Somewhere that can't be written to, so the
+                    // media fails instead of writing the chunk in the
+                    // clear
+                    Err(err) => {
+                        error!("Can't encrypt chunk {} of {}: {}", name, device_name, err);
+                        pipes.join("unavailable").join(&name)
+                    }
+                }
+            }
+        };
+        Some(location.to_string_lossy().to_string().to_value())
+    })?;
+
+    // The media already watches the bus of its pipeline, but a sync
+    // handler still gets to see the messages before that
+    let pipeline = bin
+        .get_parent()
+        .and_then(|p| p.downcast::<gst::Pipeline>().ok())
+        .ok_or(NoMediaElement)?;
+    let bus = pipeline.get_bus().ok_or(NoMediaElement)?;
+    let tracker = ChunkTracker {
+        device: device.to_string(),
+        dir: dir.to_path_buf(),
+        catalog: catalog.clone(),
+        encryptors,
+        mux: mux.clone(),
+        opened: Mutex::new(HashMap::new()),
+    };
+    bus.set_sync_handler(move |_bus, msg| {
+        if let gst::message::MessageView::Element(element) = msg.view() {
+            if let Some(s) = element.get_structure() {
+                if let Err(err) = tracker.handle(s) {
+                    error!("Can't update the catalog of {}: {}", tracker.device, err);
+                }
+            }
+        }
+        gst::BusSyncReply::Pass
+    });
+
+    Ok(())
+}
+
+/// Make a pipe for the muxer to write a chunk to, and encrypt what
+/// comes out of it into the chunk file in a thread of its own.  Each
+/// read is sealed right away, so the plain media only ever sits in
+/// memory.
+fn encrypt_through_pipe(
+    master: &MasterKey,
+    pipe: &Path,
+    dest: &Path,
+) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+    let c_pipe = CString::new(pipe.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_pipe.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let master = master.clone();
+    let pipe = pipe.to_path_buf();
+    let dest = dest.to_path_buf();
+    Ok(thread::spawn(move || {
+        let encrypted = (|| -> Result<(), Error> {
+            // Waits for the muxer to open the other end
+            let mut src = File::open(&pipe)?;
+            let mut writer = crypto::ChunkWriter::new(&master, File::create(&dest)?)?;
+            let mut buffer = vec![0; PIPE_BUFFER_SIZE];
+            loop {
+                let read = src.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                writer.write_all(&buffer[..read])?;
+            }
+            writer.finish()?.sync_all()?;
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&pipe);
+        encrypted
+    }))
+}
+
+/// Follows the chunks written by one media
+struct ChunkTracker {
+    device: String,
+    /// Where the chunks of the device are kept
+    dir: PathBuf,
+    catalog: SharedCatalog,
+    /// Threads encrypting the chunks that are open, if any
+    encryptors: Encryptors,
+    mux: gst::Element,
+    /// Running time in which each open chunk started, by location
+    opened: Mutex<HashMap<String, u64>>,
+}
+
+impl ChunkTracker {
+    fn handle(&self, s: &gst::StructureRef) -> Result<(), Error> {
+        let name = s.get_name();
+        if name != "splitmuxsink-fragment-opened" && name != "splitmuxsink-fragment-closed" {
+            return Ok(());
+        }
+        let location = s
+            .get::<&str>("location")
+            .map_err(|e| format_err!("{}", e))?
+            .unwrap_or("")
+            .to_string();
+        let running_time = s
+            .get_some::<u64>("running-time")
+            .map_err(|e| format_err!("{}", e))?;
+        let file_name = Path::new(&location)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("")
+            .to_string();
+        let start = match parse_chunk_name(&file_name) {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+
+        if name == "splitmuxsink-fragment-opened" {
+            self.opened.lock().unwrap().insert(location, running_time);
+
+            let mut chunk = Chunk::new(&self.device, &file_name, start);
+            self.describe_stream(&mut chunk);
+            self.catalog.lock().unwrap().put(chunk)?;
+            return Ok(());
+        }
+
+        let end = now_ms();
+        let opened_at = self.opened.lock().unwrap().remove(&location);
+        let mut chunk = self
+            .catalog
+            .lock()
+            .unwrap()
+            .get(&self.device, start)
+            .cloned()
+            .unwrap_or_else(|| Chunk::new(&self.device, &file_name, start));
+        chunk.end = Some(end);
+        chunk.state = ChunkState::Complete;
+        chunk.duration = opened_at.map(|t| running_time.saturating_sub(t) / 1_000_000);
+
+        // Waiting for the last records to be encrypted and hashing the
+        // file take a while, so they're done away from the streaming
+        // thread
+        let path = self.dir.join(&file_name);
+        let catalog = self.catalog.clone();
+        let encryptor = self.encryptors.lock().unwrap().remove(&location);
+        thread::spawn(move || {
+            let finished = encryptor
+                .map_or(Ok(()), |encryptor| {
+                    chunk.encrypted = true;
+                    encryptor
+                        .join()
+                        .unwrap_or_else(|_| Err(format_err!("Encryption thread panicked")))
+                })
+                .and_then(|()| Ok(std::fs::metadata(&path)?))
+                .and_then(|metadata| {
+                    chunk.size = metadata.len();
+                    chunk.checksum = Some(catalog::checksum(&path)?);
+                    // The chunk may have been pinned or flagged in the
+                    // meantime
+                    let mut catalog = catalog.lock().unwrap();
+                    if let Some(current) = catalog.get(&chunk.device, chunk.start) {
+                        chunk.pinned = current.pinned;
+                        chunk.event = current.event;
+                    }
+                    catalog.put(chunk)
+                });
+            if let Err(err) = finished {
+                error!("Can't finish chunk {:?}: {}", path, err);
+            }
+        });
+        Ok(())
+    }
+
+    /// Fill the codec and resolution of a chunk in from the caps
+    /// flowing into the muxer
+    fn describe_stream(&self, chunk: &mut Chunk) {
+        let caps = match self
+            .mux
+            .get_static_pad("video")
+            .and_then(|p| p.get_current_caps())
+        {
+            Some(caps) => caps,
+            None => return,
+        };
+        if let Some(s) = caps.get_structure(0) {
+            chunk.codec = Some(catalog::codec_name(s.get_name()));
+            chunk.width = s.get_some::<i32>("width").ok().map(|w| w as u32);
+            chunk.height = s.get_some::<i32>("height").ok().map(|h| h as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_names_carry_their_start() {
+        assert_eq!(chunk_name(1_600_000_000_123), "1600000000123.mp4");
+        assert_eq!(
+            parse_chunk_name("1600000000123.mp4"),
+            Some(1_600_000_000_123)
+        );
+        assert_eq!(parse_chunk_name(&chunk_name(0)), Some(0));
+    }
+
+    #[test]
+    fn anything_else_isnt_a_chunk() {
+        for name in &[
+            "",
+            ".mp4",
+            "mp4",
+            "123",
+            "123mp4",
+            "123.part",
+            "123.mp4.part",
+            "-123.mp4",
+            "+123.mp4",
+            "12a.mp4",
+            "1 23.mp4",
+            "../123.mp4",
+            "99999999999999999999999.mp4",
+        ] {
+            assert_eq!(parse_chunk_name(name), None, "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::thread;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use openssl::ssl::{SslConnector, SslMethod};
+
+use crate::api::CHECKSUM_HEADER;
+use crate::archive;
+use crate::catalog::{self, Chunk, ChunkState, SharedCatalog};
+use crate::model::{Config, ConfigReplication};
+
+/// Copy finished chunks and their catalog records to the peer storage
+/// in its own thread.  What the peer already has is tracked in the
+/// catalog, so replication picks up where it stopped after the peer
+/// or this storage were down.
+pub(crate) fn spawn(config: Config, catalog: SharedCatalog) -> Option<thread::JoinHandle<()>> {
+    let replication = config.replication.clone()?;
+    info!("Replicating to {}", replication.peer);
+    Some(thread::spawn(move || {
+        actix_rt::System::new().block_on(async move {
+            let client = match client() {
+                Ok(client) => client,
+                Err(err) => {
+                    error!("Can't create replication client: {}", err);
+                    return;
+                }
+            };
+            loop {
+                if let Err(err) = catch_up(&config, &replication, &catalog, &client).await {
+                    warn!("Replication to {} stopped: {}", replication.peer, err);
+                }
+                actix_rt::time::sleep(Duration::from_secs(replication.interval)).await;
+            }
+        })
+    }))
+}
+
+fn client() -> Result<awc::Client, Error> {
+    let connector = SslConnector::builder(SslMethod::tls())?.build();
+    let connector = awc::Connector::new()
+        .timeout(Duration::from_secs(15))
+        .openssl(connector);
+    Ok(awc::Client::builder()
+        .connector(connector)
+        .timeout(Duration::from_secs(120))
+        .finish())
+}
+
+/// Send everything the peer is missing, in the order the chunks were
+/// sealed, so each record links to the one the peer got before it.
+/// Records of chunks quarantined or deleted before they were sent go
+/// on their own to keep the chain whole.  The first failure ends the
+/// round, the next one starts over from there.
+async fn catch_up(
+    config: &Config,
+    replication: &ConfigReplication,
+    catalog: &SharedCatalog,
+    client: &awc::Client,
+) -> Result<(), Error> {
+    let mut pending = catalog
+        .lock()
+        .unwrap()
+        .records()
+        .filter(|chunk| is_replicated(config, replication, &chunk.device))
+        .filter(|chunk| is_stored(chunk) || chunk.replicated.is_none())
+        .filter(|chunk| chunk.chain.is_some() && chunk.replicated != Some(chunk.revision))
+        .cloned()
+        .collect::<Vec<Chunk>>();
+    pending.sort_by_key(|chunk| (chunk.device.clone(), chunk.sequence));
+
+    for chunk in pending {
+        // Chunks don't change once they're finished, only their
+        // records do
+        if chunk.replicated.is_none() && is_stored(&chunk) {
+            send_chunk(config, replication, client, &chunk).await?;
+        }
+        send_record(replication, client, &chunk).await?;
+        catalog
+            .lock()
+            .unwrap()
+            .mark_replicated(&chunk.device, chunk.start, chunk.revision)?;
+        debug!("Replicated {}/{}", chunk.device, chunk.name);
+    }
+    Ok(())
+}
+
+/// Whether the file of a chunk is still kept, here or in the archive
+fn is_stored(chunk: &Chunk) -> bool {
+    match chunk.state {
+        ChunkState::Complete | ChunkState::Repaired => true,
+        _ => false,
+    }
+}
+
+fn is_replicated(config: &Config, replication: &ConfigReplication, jid: &str) -> bool {
+    match &replication.locations {
+        None => true,
+        Some(names) => names.iter().any(|name| {
+            config
+                .locations
+                .get(name)
+                .map_or(false, |l| l.devices.iter().any(|d| d == jid))
+        }),
+    }
+}
+
+/// The chunk goes exactly as it is on disk, encrypted or not.  It's
+/// fetched from the archive if it's no longer on disk.
+async fn send_chunk(
+    config: &Config,
+    replication: &ConfigReplication,
+    client: &awc::Client,
+    chunk: &Chunk,
+) -> Result<(), Error> {
+    let fetched = archive::fetch(config, chunk).await?;
+    let path = fetched.path().to_path_buf();
+    let (data, checksum) = actix_rt::task::spawn_blocking(move || -> Result<_, Error> {
+        Ok((std::fs::read(&path)?, catalog::checksum(&path)?))
+    })
+    .await??;
+    let response = client
+        .put(url(replication, chunk, ""))
+        .bearer_auth(&replication.token)
+        .insert_header((CHECKSUM_HEADER, checksum))
+        .send_body(data)
+        .await
+        .map_err(|e| format_err!("Can't send {}: {}", chunk.name, e))?;
+    if !response.status().is_success() {
+        return Err(format_err!(
+            "Peer refused {}/{}: {}",
+            chunk.device,
+            chunk.name,
+            response.status(),
+        ));
+    }
+    Ok(())
+}
+
+async fn send_record(
+    replication: &ConfigReplication,
+    client: &awc::Client,
+    chunk: &Chunk,
+) -> Result<(), Error> {
+    let mut record = chunk.clone();
+    record.replicated = None;
+    let response = client
+        .put(url(replication, chunk, "/record"))
+        .bearer_auth(&replication.token)
+        .send_json(&record)
+        .await
+        .map_err(|e| format_err!("Can't send record of {}: {}", chunk.name, e))?;
+    if !response.status().is_success() {
+        return Err(format_err!(
+            "Peer refused record of {}/{}: {}",
+            chunk.device,
+            chunk.name,
+            response.status(),
+        ));
+    }
+    Ok(())
+}
+
+fn url(replication: &ConfigReplication, chunk: &Chunk, suffix: &str) -> String {
+    format!(
+        "{}/replica/{}/{}{}",
+        replication.peer.trim_end_matches('/'),
+        chunk.device,
+        chunk.name,
+        suffix,
+    )
+}
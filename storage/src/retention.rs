@@ -0,0 +1,162 @@
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::archive::{self, Archive};
+use crate::catalog::{Chunk, ChunkState, SharedCatalog, Tier};
+use crate::model::Config;
+use crate::record;
+use crate::thumbnail;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Apply the retention policies every `retention.interval' seconds
+/// in their own thread, so deleting files never holds the RTSP server
+/// or the HTTP API up.
+pub(crate) fn spawn(config: Config, catalog: SharedCatalog) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        if let Err(err) = apply(&config, &catalog) {
+            error!("Can't apply retention policies: {}", err);
+        }
+        thread::sleep(Duration::from_secs(config.retention.interval));
+    })
+}
+
+/// Delete the chunks that are older than their device is allowed to
+/// keep, then make room until the chunks on disk fit in
+/// `retention.maxspace'.  Chunks that are also in the archive are
+/// evicted from disk first, oldest first, and only then the oldest
+/// chunks are deleted.  Chunks still being written, pinned chunks
+/// and, unless told otherwise, chunks flagged as events are never
+/// deleted.
+fn apply(config: &Config, catalog: &SharedCatalog) -> Result<(), Error> {
+    let now = record::now_ms();
+    let mut chunks = catalog
+        .lock()
+        .unwrap()
+        .chunks()
+        .cloned()
+        .collect::<Vec<Chunk>>();
+    chunks.sort_by_key(|chunk| chunk.start);
+
+    let mut total: u64 = chunks
+        .iter()
+        .filter(|chunk| chunk.tier != Tier::Archived)
+        .map(|chunk| chunk.size)
+        .sum();
+    let mut kept = Vec::with_capacity(chunks.len());
+
+    for chunk in chunks {
+        let expired = match (config.keep_days(&chunk.device), chunk.end) {
+            (Some(days), Some(end)) => end + days * DAY_MS < now,
+            _ => false,
+        };
+        if expired && is_deletable(config, &chunk) {
+            let days = config.keep_days(&chunk.device).unwrap_or(0);
+            let reason = format!("older than {} days", days);
+            if delete(config, catalog, &chunk, &reason)? && chunk.tier != Tier::Archived {
+                total = total.saturating_sub(chunk.size);
+            }
+        } else {
+            kept.push(chunk);
+        }
+    }
+
+    let maxspace = match config.retention.maxspace {
+        Some(maxspace) => maxspace,
+        None => return Ok(()),
+    };
+    for chunk in kept.iter().filter(|chunk| chunk.tier == Tier::Both) {
+        if total <= maxspace {
+            break;
+        }
+        if evict(config, catalog, chunk)? {
+            total = total.saturating_sub(chunk.size);
+        }
+    }
+    for chunk in kept.iter().filter(|chunk| chunk.tier == Tier::Local) {
+        if total <= maxspace {
+            break;
+        }
+        if is_deletable(config, chunk) && delete(config, catalog, chunk, "storage above maxspace")?
+        {
+            total = total.saturating_sub(chunk.size);
+        }
+    }
+    if total > maxspace {
+        warn!(
+            "Storage takes {} bytes, above maxspace of {}, with nothing left to delete",
+            total, maxspace,
+        );
+    }
+    Ok(())
+}
+
+fn is_deletable(config: &Config, chunk: &Chunk) -> bool {
+    chunk.end.is_some() && !chunk.pinned && !(chunk.event && config.retention.keep_events)
+}
+
+/// Mark a chunk as deleted in the catalog and remove it from disk and
+/// from the archive.  It's checked again with the catalog locked
+/// since it might have been pinned in the meantime.  Returns whether
+/// the chunk was deleted.
+fn delete(
+    config: &Config,
+    catalog: &SharedCatalog,
+    chunk: &Chunk,
+    reason: &str,
+) -> Result<bool, Error> {
+    {
+        let mut catalog = catalog.lock().unwrap();
+        let mut deleted = match catalog.get(&chunk.device, chunk.start) {
+            Some(current) if is_deletable(config, current) => current.clone(),
+            _ => return Ok(false),
+        };
+        deleted.state = ChunkState::Deleted;
+        catalog.put(deleted)?;
+    }
+
+    remove_file(config, chunk)?;
+    thumbnail::remove(config, chunk)?;
+    if chunk.tier != Tier::Local {
+        if let Some(archive) = Archive::open(config)? {
+            archive::block_on(archive.remove(chunk))?;
+        }
+    }
+    info!(
+        "Deleted chunk {}/{} ({} bytes): {}",
+        chunk.device, chunk.name, chunk.size, reason,
+    );
+    Ok(true)
+}
+
+/// Remove a chunk that's also in the archive from disk.  Returns
+/// whether the chunk was evicted.
+fn evict(config: &Config, catalog: &SharedCatalog, chunk: &Chunk) -> Result<bool, Error> {
+    {
+        let mut catalog = catalog.lock().unwrap();
+        let mut evicted = match catalog.get(&chunk.device, chunk.start) {
+            Some(current) if current.tier == Tier::Both => current.clone(),
+            _ => return Ok(false),
+        };
+        evicted.tier = Tier::Archived;
+        catalog.put(evicted)?;
+    }
+
+    remove_file(config, chunk)?;
+    info!(
+        "Evicted chunk {}/{} ({} bytes) from disk, it's kept in the archive",
+        chunk.device, chunk.name, chunk.size,
+    );
+    Ok(true)
+}
+
+fn remove_file(config: &Config, chunk: &Chunk) -> Result<(), Error> {
+    let path = config.device_path(&chunk.device).join(&chunk.name);
+    match std::fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
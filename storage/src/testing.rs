@@ -0,0 +1,58 @@
+//! Helpers shared by the tests of the other modules
+
+use std::path::{Path, PathBuf};
+
+use crate::model::Config;
+use crate::playback;
+
+/// JID of the device the configuration of the tests records
+pub(crate) const DEVICE: &str = "cam001@studio.loc";
+
+/// `encryption.key' for the tests that encrypt chunks
+pub(crate) const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// Empty directory of a test's own, under the system's temporary
+/// directory
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ucam-storage-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Configuration that keeps the chunks of `cam001@studio.loc' under
+/// `dir', followed by whatever sections `extra' has
+pub(crate) fn config(dir: &Path, extra: &str) -> Config {
+    let text = format!(
+        "[http]\n\
+         host = 'localhost'\n\
+         port = 9901\n\
+         [api]\n\
+         host = 'localhost'\n\
+         port = 9902\n\
+         [storage]\n\
+         path = '{}'\n\
+         [locations.studio]\n\
+         devices = ['cam001@studio.loc']\n\
+         {}\n",
+        dir.display(),
+        extra,
+    );
+    toml::from_str(&text).unwrap()
+}
+
+/// Write a few seconds of H.264 into a fragmented MP4 file, the way
+/// chunks are recorded
+pub(crate) fn record_fragmented(path: &Path) {
+    gst::init().unwrap();
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=50 ! video/x-raw,width=320,height=240,framerate=10/1 ! \
+         x264enc key-int-max=10 ! h264parse ! mp4mux fragment-duration=1000 ! \
+         filesink location={}",
+        path.display(),
+    ))
+    .unwrap()
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    playback::run(&pipeline).unwrap();
+}
@@ -0,0 +1,243 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use gst::prelude::*;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+use crate::catalog::{Chunk, ChunkState, SharedCatalog, Tier};
+use crate::crypto;
+use crate::model::Config;
+use crate::NoSuchElement;
+
+/// Extension of the thumbnail files
+const THUMBNAIL_EXTENSION: &str = "jpg";
+
+/// Seconds between each look for chunks without thumbnails
+const SCAN_INTERVAL: u64 = 10;
+
+/// Thumbnails are named after the wall-clock time, in milliseconds,
+/// of the frame they show
+fn thumbnail_name(timestamp: u64) -> String {
+    format!("{}.{}", timestamp, THUMBNAIL_EXTENSION)
+}
+
+fn parse_thumbnail_name(name: &str) -> Option<u64> {
+    name.strip_suffix(THUMBNAIL_EXTENSION)?
+        .strip_suffix('.')?
+        .parse()
+        .ok()
+}
+
+/// Extract thumbnails out of the chunks that don't have them yet, in
+/// their own thread, oldest chunks first
+pub(crate) fn spawn(config: Config, catalog: SharedCatalog) -> Option<thread::JoinHandle<()>> {
+    config.thumbnails.as_ref()?;
+    Some(thread::spawn(move || loop {
+        let pending = catalog
+            .lock()
+            .unwrap()
+            .chunks()
+            .filter(|chunk| !chunk.thumbnails && chunk.end.is_some())
+            .filter(|chunk| match chunk.state {
+                ChunkState::Complete | ChunkState::Repaired => true,
+                _ => false,
+            })
+            .cloned()
+            .collect::<Vec<Chunk>>();
+
+        for chunk in pending {
+            match extract(&config, &chunk) {
+                Ok(count) => debug!(
+                    "Extracted {} thumbnails from {}/{}",
+                    count, chunk.device, chunk.name
+                ),
+                Err(err) => warn!(
+                    "Can't extract thumbnails from {}/{}: {}",
+                    chunk.device, chunk.name, err
+                ),
+            }
+            // Chunks thumbnails can't be extracted from aren't tried
+            // over and over again
+            let mut catalog = catalog.lock().unwrap();
+            if let Some(current) = catalog.get(&chunk.device, chunk.start) {
+                let mut current = current.clone();
+                current.thumbnails = true;
+                if let Err(err) = catalog.put(current) {
+                    error!("Can't update the catalog: {}", err);
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(SCAN_INTERVAL));
+    }))
+}
+
+/// Save one JPEG every `thumbnails.interval' seconds of a chunk.  The
+/// pipeline seeks to the key frame right before each of those points
+/// and only that frame gets decoded.  Thumbnails of encrypted chunks
+/// are encrypted as well.  Chunks get here well before they're
+/// archived, so the ones no longer on disk are skipped rather than
+/// downloaded.
+fn extract(config: &Config, chunk: &Chunk) -> Result<usize, Error> {
+    let settings = config
+        .thumbnails
+        .as_ref()
+        .ok_or_else(|| format_err!("Thumbnails aren't configured"))?;
+    let path = config.device_path(&chunk.device).join(&chunk.name);
+    if chunk.tier == Tier::Archived || !path.exists() {
+        return Err(format_err!("Chunk isn't on disk"));
+    }
+    let plain = crypto::PlainFile::open(config, &path)?;
+    let master = crypto::master_key(config)?.filter(|_| chunk.encrypted);
+    let dir = config.thumbnail_path(&chunk.device);
+    std::fs::create_dir_all(&dir)?;
+
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc name=src ! qtdemux ! h264parse ! avdec_h264 ! videoconvert ! \
+         videoscale ! video/x-raw,width={},pixel-aspect-ratio=1/1 ! \
+         jpegenc quality=80 ! appsink name=sink sync=false",
+        settings.width,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .expect("not a pipeline");
+    pipeline
+        .get_by_name("src")
+        .ok_or_else(|| NoSuchElement("src".to_string()))?
+        .set_property("location", &plain.path().to_string_lossy().to_string())?;
+    let sink = pipeline
+        .get_by_name("sink")
+        .and_then(|e| e.downcast::<gst_app::AppSink>().ok())
+        .ok_or_else(|| NoSuchElement("sink".to_string()))?;
+
+    let result = (|| -> Result<usize, Error> {
+        pause(&pipeline)?;
+        let duration = chunk.duration.unwrap_or(0);
+        let mut last = None;
+        let mut count = 0;
+        for offset in (0..duration.max(1)).step_by((settings.interval * 1000).max(1) as usize) {
+            pipeline.seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                gst::ClockTime::from_mseconds(offset),
+            )?;
+            pause(&pipeline)?;
+            let sample = match sink.pull_preroll() {
+                Some(sample) => sample,
+                None => break,
+            };
+            let buffer = sample
+                .get_buffer()
+                .ok_or_else(|| format_err!("Sample without buffer"))?;
+            // Points close together may share the same key frame
+            let pts = buffer.get_pts().mseconds().unwrap_or(offset);
+            if last == Some(pts) {
+                continue;
+            }
+            last = Some(pts);
+
+            let map = buffer
+                .map_readable()
+                .ok_or_else(|| format_err!("Can't read thumbnail"))?;
+            let data = match &master {
+                Some(master) => crypto::encrypt(master, map.as_slice())?,
+                None => map.as_slice().to_vec(),
+            };
+            std::fs::write(dir.join(thumbnail_name(chunk.start + pts)), data)?;
+            count += 1;
+        }
+        Ok(count)
+    })();
+
+    let _ = pipeline.set_state(gst::State::Null);
+    result
+}
+
+/// Bring a pipeline to paused and wait for it to get there
+fn pause(pipeline: &gst::Pipeline) -> Result<(), Error> {
+    pipeline
+        .set_state(gst::State::Paused)
+        .map_err(|_| format_err!("Can't pause pipeline"))?;
+    let (result, _, _) = pipeline.get_state(gst::CLOCK_TIME_NONE);
+    result.map_err(|_| format_err!("Pipeline didn't preroll"))?;
+    Ok(())
+}
+
+/// Timestamps of the thumbnails of a device within a time range,
+/// oldest first
+pub(crate) fn list(config: &Config, jid: &str, start: u64, end: u64) -> Result<Vec<u64>, Error> {
+    let dir = config.thumbnail_path(jid);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut timestamps = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(timestamp) = name.to_str().and_then(parse_thumbnail_name) {
+            if timestamp >= start && timestamp < end {
+                timestamps.push(timestamp);
+            }
+        }
+    }
+    timestamps.sort();
+    Ok(timestamps)
+}
+
+/// A single thumbnail as a JPEG, decrypted if needed
+pub(crate) fn read(config: &Config, jid: &str, timestamp: u64) -> Result<Vec<u8>, Error> {
+    let data = std::fs::read(thumbnail_path(config, jid, timestamp))?;
+    if !crypto::is_encrypted_data(&data) {
+        return Ok(data);
+    }
+    let master = crypto::master_key(config)?.ok_or(crypto::NoKey)?;
+    crypto::decrypt(&master, &data)
+}
+
+/// Put thumbnails side by side in a JPEG, `columns' per row, in the
+/// order they're given.  Every tile takes the size of the first
+/// thumbnail.
+pub(crate) fn sprite(
+    config: &Config,
+    jid: &str,
+    timestamps: &[u64],
+    columns: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut tiles = timestamps
+        .iter()
+        .map(|t| Ok(image::load_from_memory(&read(config, jid, *t)?)?))
+        .collect::<Result<Vec<DynamicImage>, Error>>()?;
+    let (width, height) = match tiles.first() {
+        Some(first) => (first.width(), first.height()),
+        None => return Err(format_err!("No thumbnails")),
+    };
+    let columns = columns.max(1).min(tiles.len() as u32);
+    let rows = (tiles.len() as u32 + columns - 1) / columns;
+
+    let mut sheet = RgbImage::new(width * columns, height * rows);
+    for (i, tile) in tiles.drain(..).enumerate() {
+        let tile = tile
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgb8();
+        let (x, y) = (i as u32 % columns * width, i as u32 / columns * height);
+        image::imageops::replace(&mut sheet, &tile, x as i64, y as i64);
+    }
+
+    let mut data = vec![];
+    DynamicImage::ImageRgb8(sheet)
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(80))?;
+    Ok(data)
+}
+
+/// Remove the thumbnails of a chunk that's gone
+pub(crate) fn remove(config: &Config, chunk: &Chunk) -> Result<(), Error> {
+    let end = chunk.end.unwrap_or(chunk.start) + 1;
+    for timestamp in list(config, &chunk.device, chunk.start, end)? {
+        std::fs::remove_file(thumbnail_path(config, &chunk.device, timestamp))?;
+    }
+    Ok(())
+}
+
+fn thumbnail_path(config: &Config, jid: &str, timestamp: u64) -> PathBuf {
+    config.thumbnail_path(jid).join(thumbnail_name(timestamp))
+}
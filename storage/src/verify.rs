@@ -0,0 +1,200 @@
+use failure::Error;
+
+use crate::catalog::{self, ChunkState, Tier};
+use crate::model::Config;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Found {} problems in the chain of {}", _0, _1)]
+pub(crate) struct VerifyFailed(usize, String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Chains are off, `chain.secret' isn't set")]
+pub(crate) struct ChainsOff;
+
+/// Walk the chain of a device, in the order its chunks were finished,
+/// and report every record that doesn't follow from the one before
+/// it, every record missing from the chain and every file that was
+/// deleted or doesn't match its record anymore.  It only reads, so
+/// it can run alongside storage.
+pub(crate) fn verify(config: &Config, device: &str) -> Result<(), Error> {
+    let chunks = catalog::read_history(config, device)?;
+    let mut problems = 0;
+    let mut sealed = 0;
+    let mut last: Option<(u64, String)> = None;
+    let secret = match &config.chain {
+        Some(chain) => chain.secret.as_str(),
+        None => return Err(ChainsOff.into()),
+    };
+
+    for chunk in &chunks {
+        let (sequence, link) = match (chunk.sequence, &chunk.chain) {
+            (Some(sequence), Some(link)) => (sequence, link),
+            _ => {
+                println!("{}: not sealed yet", chunk.name);
+                continue;
+            }
+        };
+        sealed += 1;
+
+        let expected = last.as_ref().map(|(s, _)| s + 1).unwrap_or(0);
+        if sequence > expected {
+            println!(
+                "{}: gap, {} records missing before it",
+                chunk.name,
+                sequence - expected,
+            );
+            problems += 1;
+        } else if sequence < expected {
+            println!("{}: sequence {} repeated", chunk.name, sequence);
+            problems += 1;
+        }
+        if chunk.previous.as_deref() != last.as_ref().map(|(_, l)| l.as_str()) {
+            println!("{}: doesn't link to the record before it", chunk.name);
+            problems += 1;
+        }
+        if &catalog::chain_link(secret, chunk)? != link {
+            println!("{}: record was modified", chunk.name);
+            problems += 1;
+        }
+        last = Some((sequence, link.clone()));
+
+        let path = match chunk.state {
+            ChunkState::Deleted => {
+                println!("{}: deleted by the retention policies", chunk.name);
+                continue;
+            }
+            _ if chunk.tier == Tier::Archived => {
+                println!("{}: only in the archive, not checked", chunk.name);
+                continue;
+            }
+            ChunkState::Quarantined => config.quarantine_path(device).join(&chunk.name),
+            _ => config.device_path(device).join(&chunk.name),
+        };
+        if !path.exists() {
+            println!("{}: file was deleted", chunk.name);
+            problems += 1;
+        } else if Some(catalog::checksum(&path)?) != chunk.checksum {
+            println!("{}: file was modified", chunk.name);
+            problems += 1;
+        }
+    }
+
+    println!("{} chunks of {} verified", sealed, device);
+    if problems > 0 {
+        return Err(VerifyFailed(problems, device.to_string()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::catalog::{Catalog, Chunk};
+    use crate::testing::{self, DEVICE};
+
+    /// Storage with three finished chunks of `DEVICE', sealed with a
+    /// `chain.secret'
+    fn chained(name: &str) -> Config {
+        let dir = testing::scratch_dir(name);
+        let config = testing::config(&dir, "[chain]\nsecret = 'south'");
+        let mut catalog = Catalog::open(&config).unwrap();
+        std::fs::create_dir_all(config.device_path(DEVICE)).unwrap();
+        for start in &[1000, 2000, 3000] {
+            let name = format!("{}.mp4", start);
+            let path = config.device_path(DEVICE).join(&name);
+            std::fs::write(&path, format!("media of {}", start)).unwrap();
+            let mut chunk = Chunk::new(DEVICE, &name, *start);
+            chunk.end = Some(start + 1000);
+            chunk.duration = Some(1000);
+            chunk.size = std::fs::metadata(&path).unwrap().len();
+            chunk.checksum = Some(catalog::checksum(&path).unwrap());
+            chunk.state = ChunkState::Complete;
+            catalog.put(chunk).unwrap();
+        }
+        config
+    }
+
+    /// Rewrite the records of the catalog, last revision of each
+    /// chunk only, in the order of the chain
+    fn rewrite(config: &Config, change: impl FnOnce(&mut Vec<Chunk>)) {
+        let mut chunks = catalog::read_history(config, DEVICE).unwrap();
+        change(&mut chunks);
+        let lines = chunks
+            .iter()
+            .map(|chunk| serde_json::to_string(chunk).unwrap() + "\n")
+            .collect::<String>();
+        std::fs::write(Path::new(&config.storage.path).join("catalog.jsonl"), lines).unwrap();
+    }
+
+    fn failed(config: &Config) -> bool {
+        match verify(config, DEVICE) {
+            Ok(()) => false,
+            Err(err) => err.downcast_ref::<VerifyFailed>().is_some(),
+        }
+    }
+
+    #[test]
+    fn chains_as_sealed_verify() {
+        let config = chained("verify-sealed");
+        let chunks = catalog::read_history(&config, DEVICE).unwrap();
+        let sequences = chunks.iter().map(|c| c.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences, vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(chunks[0].previous, None);
+        assert_eq!(chunks[1].previous, chunks[0].chain);
+        verify(&config, DEVICE).unwrap();
+    }
+
+    #[test]
+    fn links_depend_on_the_secret() {
+        let config = chained("verify-secret");
+        let chunk = &catalog::read_history(&config, DEVICE).unwrap()[0];
+        let link = catalog::chain_link("south", chunk).unwrap();
+        assert_eq!(chunk.chain.as_ref(), Some(&link));
+        assert_ne!(catalog::chain_link("north", chunk).unwrap(), link);
+    }
+
+    #[test]
+    fn modified_records_fail() {
+        let config = chained("verify-modified");
+        rewrite(&config, |chunks| chunks[1].duration = Some(10));
+        assert!(failed(&config));
+    }
+
+    #[test]
+    fn deleted_records_fail() {
+        let config = chained("verify-deleted");
+        rewrite(&config, |chunks| {
+            chunks.remove(1);
+        });
+        assert!(failed(&config));
+    }
+
+    #[test]
+    fn reordered_records_fail() {
+        let config = chained("verify-reordered");
+        rewrite(&config, |chunks| {
+            let first = chunks[1].sequence;
+            chunks[1].sequence = chunks[2].sequence;
+            chunks[2].sequence = first;
+        });
+        assert!(failed(&config));
+    }
+
+    #[test]
+    fn modified_files_fail() {
+        let config = chained("verify-file");
+        let path = config.device_path(DEVICE).join("2000.mp4");
+        std::fs::write(path, "other media").unwrap();
+        assert!(failed(&config));
+    }
+
+    #[test]
+    fn chains_off_dont_verify() {
+        let dir = testing::scratch_dir("verify-off");
+        let config = testing::config(&dir, "");
+        let err = verify(&config, DEVICE).unwrap_err();
+        assert!(err.downcast_ref::<ChainsOff>().is_some());
+    }
+}
@@ -9,6 +9,7 @@ import Typography from '@material-ui/core/Typography';
 import Container from '@material-ui/core/Container';
 import Divider from '@material-ui/core/Divider';
 import Paper from '@material-ui/core/Paper';
+import Button from '@material-ui/core/Button';
 
 import List from '@material-ui/core/List';
 import ListItem from '@material-ui/core/ListItem';
@@ -60,6 +61,10 @@ function ClientItem({ jid, caps }) {
 
   const isConnectedTo = wsIsConnected(jid);
 
+  // storage tells when a device stops recording
+  const outage = state.outages[jid];
+  const moving = state.motion[jid] !== undefined;
+
   const handleItemClick = () => isConnectedTo
     ? dispatch(messages.disconnect(jid))
     : dispatch(messages.connect(jid));
@@ -68,6 +73,9 @@ function ClientItem({ jid, caps }) {
     <ListItem button component="li" onClick={handleItemClick}>
       <ListItemText
         primary={jid}
+        secondary={outage !== undefined
+                   ? `Not recording since ${new Date(outage).toLocaleString()}`
+                   : moving && 'Motion'}
         style={{ overflow: 'hidden', textOverflow: 'ellipsis', marginRight: 20 }}
       />
       <ListItemSecondaryAction>
@@ -105,20 +113,24 @@ const ClientCardShell = styled.div`
   }
 `;
 
+// Phones on mobile data start with the lighter encoding of the video,
+// when the browser tells what the link is
+function initialQuality() {
+  const connection = navigator.connection;
+  return connection && connection.type === 'cellular' ? 'low' : 'high';
+}
+
 function ClientCard({ jid }) {
   const { dispatch, state } = useContext(appContext);
   const [loading, setLoading] = useState(false);
+  const [quality, setQuality] = useState(initialQuality);
   const videoEl = useRef(null);
 
   // Entry point of the WebRTC conversation. We create a peer connection
   // for intermediating the conversation with the peer identified by the
   // `client' parameter received above.
   useEffect(() => {
-    const pc = new RTCPeerConnection({
-      // iceServers: [{
-      //   urls: "stun:stun.l.google.com"
-      // }]
-    });
+    const pc = new RTCPeerConnection();
 
     pc.onconnectionstatechange = event => {
       console.log(`onconnectionstatechange: state=${pc.connectionState}`);
@@ -169,12 +181,38 @@ function ClientCard({ jid }) {
       createOffer();
     };
 
-    dispatch(messages.wsSend('peerrequestcall', jid));
+    // The call only starts once the ICE servers, and the TURN
+    // credentials, the server hands out are in place
+    api
+      .iceServers(state.authToken)
+      .then(iceServers => pc.setConfiguration({ iceServers }))
+      .catch(error => console.error('Fetching ICE servers failed: ', error))
+      .finally(() => {
+        dispatch(messages.wsSend({ peerquality: quality }, jid));
+        dispatch(messages.wsSend('peerrequestcall', jid));
+      });
 
     dispatch(messages.wrtcConnection(jid, pc))
 
   }, []);
 
+  // The device switches the quality without hanging up
+  const switchQuality = () => {
+    const next = quality === 'high' ? 'low' : 'high';
+    setQuality(next);
+    dispatch(messages.wsSend({ peerquality: next }, jid));
+  };
+
+  // Flag what's on screen right now, storage keeps the footage around
+  // it from being deleted
+  const bookmark = () => {
+    const label = window.prompt('What happened?');
+    if (label === null) return;
+    dispatch(messages.wsSend({
+      bookmark: { device: jid, timestamp: Date.now(), label },
+    }));
+  };
+
   return (
     <Paper>
       <ClientCardShell>
@@ -191,6 +229,18 @@ function ClientCard({ jid }) {
            ref={videoEl}>
          </video>
         }
+
+        {!loading &&
+         <Button variant="outlined" onClick={bookmark}>
+           Bookmark
+         </Button>
+        }
+
+        {!loading &&
+         <Button variant="outlined" onClick={switchQuality}>
+           {quality === 'high' ? 'Low quality' : 'High quality'}
+         </Button>
+        }
       </ClientCardShell>
     </Paper>
   );
@@ -6,6 +6,9 @@ export const PEER_LIST =    "PEER_LIST";
 export const PEER_ONLINE =  "PEER_ONLINE";
 export const PEER_OFFLINE = "PEER_OFFLINE";
 
+export const RECORDING_GAP = "RECORDING_GAP";
+export const MOTION =        "MOTION";
+
 export const WSCK_CONNECT =    "WSCK_CONNECT";
 export const WSCK_SEND =       "WSCK_SEND";
 export const WSCK_ON_OPEN =    "WSCK_ON_OPEN";
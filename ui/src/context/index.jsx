@@ -32,6 +32,12 @@ const initialState = {
   /// map of JID's to PeerState entries.  Being `undefined' means
   // being disconnected
   wsStateByID: {},
+  /// map of device JID's to the time, in milliseconds, since which
+  /// storage hasn't received anything from them
+  outages: {},
+  /// map of device JID's to the time, in milliseconds, in which the
+  /// motion they're seeing started
+  motion: {},
 };
 
 /// Where globally accessible data of the application is kept
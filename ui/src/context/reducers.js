@@ -31,6 +31,25 @@ export function createReducer() {
       return newState;
     }
 
+    case actions.RECORDING_GAP: {
+      const newState = { ...state, outages: { ...state.outages } };
+      const { device, start, end } = data.message.recordinggap;
+      if (end === null || end === undefined)
+        newState.outages[device] = start;
+      else
+        delete newState.outages[device];
+      return newState;
+    }
+
+    case actions.MOTION: {
+      const newState = { ...state, motion: { ...state.motion } };
+      if (data.message.motionstart !== undefined)
+        newState.motion[data.from_jid] = data.message.motionstart.timestamp;
+      else
+        delete newState.motion[data.from_jid];
+      return newState;
+    }
+
     case actions.WSCK_CONNECT: {
       const newState = { ...state };
       newState.ws.current = data.ws;
@@ -45,6 +45,16 @@ function makeMessageHandler(state, dispatch) {
         return;
       }
 
+      if (message.recordinggap !== undefined) {
+        dispatch({ type: actions.RECORDING_GAP, ...data });
+        return;
+      }
+
+      if (message.motionstart !== undefined || message.motionstop !== undefined) {
+        dispatch({ type: actions.MOTION, ...data });
+        return;
+      }
+
       // handle messages related to the call flow
 
       const wsPeer = state.wsPeersByID?.current[fromJID];
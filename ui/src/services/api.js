@@ -3,3 +3,9 @@ export async function peers(token) {
   const response = await window.fetch('/api/peers', { headers });
   return await response.json();
 }
+
+export async function iceServers(token) {
+  const headers = new Headers({ Authorization: `Bearer ${btoa(token)}` });
+  const response = await window.fetch('/api/ice-servers', { headers });
+  return await response.json();
+}